@@ -0,0 +1,57 @@
+//! Throughput of the crate's hot paths: `cargo bench`.
+//!
+//! Each benchmark is run for a fixed number of iterations after a warm-up,
+//! and reports the mean rate. Live benchmarks need milk on the PATH.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use milkrs::Milk;
+
+/// Time `iterations` calls of `f` after `iterations / 10` warm-up calls.
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    for _ in 0..iterations / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let per_iter = elapsed / iterations;
+    println!(
+        "{name:<32} {:>12.0} /s {:>12.3?} /iter",
+        iterations as f64 / elapsed.as_secs_f64(), per_iter,
+    );
+}
+
+fn main() {
+    let mut dry = Milk::builder().dry_run(true).build().expect("couldn't start dry run");
+    bench("cmd (dry run)", 1_000_000, || {
+        dry.cmd(black_box("imcp2shm out1 outs1")).unwrap();
+    });
+
+    let mut milk = match Milk::new() {
+        Ok(milk) => milk,
+        Err(e) => {
+            eprintln!("skipping live benchmarks, couldn't start milk: {e}");
+            return;
+        }
+    };
+    bench("cmd (fifo)", 100_000, || {
+        milk.cmd(black_box("listim")).unwrap();
+    });
+    let batch = vec!["listim"; 1000];
+    bench("cmds, 1000 per batch (fifo)", 100, || {
+        milk.cmds(black_box(batch.clone())).unwrap();
+    });
+    milk.sync().unwrap();
+    bench("sync latency", 200, || {
+        milk.sync().unwrap();
+    });
+    let start = Instant::now();
+    for _ in 0..10_000 {
+        milk.cmd("listim").unwrap();
+    }
+    milk.sync().unwrap();
+    let drained = start.elapsed().max(Duration::from_nanos(1));
+    println!("{:<32} {:>12.0} /s", "cmd executed (fifo + sync)", 10_000.0 / drained.as_secs_f64());
+}
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use milkrs::sanitize;
+
+fuzz_target!(|arg: &str| {
+    // anything quote() accepts must reach milk as exactly that one argument
+    if let Ok(quoted) = sanitize::quote(arg) {
+        let command = format!("loadfits {quoted} im");
+        assert!(sanitize::validate(&command).is_ok(), "{command:?} rejected");
+        assert_eq!(sanitize::split(&command), ["loadfits", arg, "im"]);
+    }
+    if sanitize::word(arg).is_ok() {
+        assert_eq!(sanitize::split(arg), [arg]);
+    }
+    // a command which passes validation is a single line
+    if sanitize::validate(arg).is_ok() {
+        assert!(!arg.contains(['\n', '\r', '\0']));
+    }
+});
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sink for a structured audit trail of a Milk session, emitting one JSON
+/// object per line for each spawn, command, sync and exit. Each record has
+/// a `timestamp` (seconds since the unix epoch), the `session` id, the
+/// session's `label` (see `MilkBuilder::label()`), the `event` name, any
+/// event-specific fields, and an `outcome` of "ok" or "error" (with
+/// an `error` message in the latter case).
+///
+/// Cloned logs share the same sink, so several sessions can write to one file.
+///
+/// # Example
+/// ```
+/// use milkrs::{Milk, AuditLog};
+/// let audit = AuditLog::to_file("/tmp/milk_audit.jsonl").unwrap();
+/// let mut milk = Milk::builder().audit_log(audit).build().unwrap();
+/// milk.cmd("mk2Dim im1 64 64").unwrap();
+/// // {"timestamp":1697000000.123,"session":"1234-0","label":"1234-0","event":"command","command":"mk2Dim im1 64 64","outcome":"ok"}
+/// ```
+#[derive(Clone)]
+pub struct AuditLog {
+    sink: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Audit log written to an arbitrary sink.
+    pub fn new(sink: impl Write + Send + 'static) -> Self {
+        Self {
+            sink: Arc::new(Mutex::new(sink)),
+        }
+    }
+
+    /// Audit log appended to the file at `path`, which is created if needed.
+    pub fn to_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Write one record. Failing to write the audit trail must never take
+    /// down the session it is auditing, so errors are ignored.
+    pub(crate) fn record(
+        &self,
+        session: &str,
+        label: &str,
+        event: &str,
+        fields: &[(&str, &str)],
+        error: Option<&str>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs_f64())
+            .unwrap_or_default();
+        let mut line = format!(
+            "{{\"timestamp\":{timestamp:.6},\"session\":{},\"label\":{},\"event\":{}",
+            quote(session), quote(label), quote(event),
+        );
+        for (key, value) in fields {
+            line += &format!(",{}:{}", quote(key), quote(value));
+        }
+        match error {
+            None => line += ",\"outcome\":\"ok\"}\n",
+            Some(error) => line += &format!(",\"outcome\":\"error\",\"error\":{}}}\n", quote(error)),
+        }
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = sink.write_all(line.as_bytes());
+            let _ = sink.flush();
+        }
+    }
+}
+
+/// One parsed line of an audit log, as written by `AuditLog`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// seconds since the unix epoch
+    pub timestamp: f64,
+    pub session: String,
+    /// the session's label (see `MilkBuilder::label()`), None in logs
+    /// written before labels were recorded
+    pub label: Option<String>,
+    pub event: String,
+    /// event-specific fields, e.g., `command` for command events
+    pub fields: Vec<(String, String)>,
+    /// error message, if the event failed
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// Parse a single audit log line, returning None if it isn't a record.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut record = Self {
+            timestamp: 0.0,
+            session: String::new(),
+            label: None,
+            event: String::new(),
+            fields: Vec::new(),
+            error: None,
+        };
+        let mut chars = line.trim().chars().peekable();
+        if chars.next()? != '{' {
+            return None;
+        }
+        loop {
+            let key = unquote(&mut chars)?;
+            if chars.next()? != ':' {
+                return None;
+            }
+            let value = if chars.peek() == Some(&'"') {
+                unquote(&mut chars)?
+            } else {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| *c != ',' && *c != '}') {
+                    number.push(c);
+                }
+                number
+            };
+            match key.as_str() {
+                "timestamp" => record.timestamp = value.parse().ok()?,
+                "session" => record.session = value,
+                "label" => record.label = Some(value),
+                "event" => record.event = value,
+                "outcome" => {}
+                "error" => record.error = Some(value),
+                _ => record.fields.push((key, value)),
+            }
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+        Some(record)
+    }
+
+    /// Value of the event-specific field `key`, if present.
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Read a JSON string literal (as written by `quote()`) off the front of `chars`.
+fn unquote(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    s.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => s.push(c),
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+/// Quote and escape a string as a JSON string literal.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, AuditLog, AuditRecord};
+    use std::fs;
+
+    #[test]
+    fn quotes_json_strings(){
+        assert_eq!(quote("writef2file \"/tmp/a.txt\" 1"), r#""writef2file \"/tmp/a.txt\" 1""#);
+        assert_eq!(quote("a\nb\u{1}"), r#""a\nb\u0001""#);
+    }
+
+    #[test]
+    fn records_round_trip(){
+        let path = "/tmp/milkrs_audit_round_trip.jsonl";
+        let _ = fs::remove_file(path);
+        let audit = AuditLog::to_file(path).expect("couldn't open log");
+        audit.record("42-0", "aol0", "command", &[("command", "writef2file \"/tmp/a\u{1}.txt\" 1")], None);
+        audit.record("42-0", "aol0", "exit", &[], Some("exit status: 1"));
+        let log = fs::read_to_string(path).expect("couldn't read log");
+        let records: Vec<AuditRecord> = log.lines().map(|line| AuditRecord::parse(line).unwrap()).collect();
+        assert_eq!(records[0].session, "42-0");
+        assert_eq!(records[0].label.as_deref(), Some("aol0"));
+        assert_eq!(records[0].event, "command");
+        assert_eq!(records[0].field("command"), Some("writef2file \"/tmp/a\u{1}.txt\" 1"));
+        assert_eq!(records[0].error, None);
+        assert_eq!(records[1].error.as_deref(), Some("exit status: 1"));
+        assert!(records[1].timestamp >= records[0].timestamp);
+        assert_eq!(AuditRecord::parse("mk2Dim im1 64 64"), None);
+    }
+}
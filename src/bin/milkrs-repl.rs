@@ -0,0 +1,314 @@
+//! Interactive prompt onto a managed milk session.
+//!
+//! Anything typed at the prompt is sent to milk as a command, and what milk
+//! prints is shown before the next prompt. Lines starting with `:` are
+//! meta-commands handled here instead; see `:help`.
+//!
+//! On a terminal the prompt is line-edited: Up and Down recall earlier
+//! commands, and Tab completes the word being typed - meta-commands and
+//! commands sent so far for the first word, images made in the session for
+//! the others. Milk has no way to list the commands it knows, so commands
+//! not yet sent aren't completed.
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::mem;
+use std::process::ExitCode;
+use milkrs::{Milk, StreamName};
+
+const HELP: &str = "\
+:sync          wait for milk to finish every command sent so far
+:streams       list the shared memory streams in $MILK_SHM_DIR
+:stats <image> print milk's statistics of an image (imstats)
+:history       print the commands sent this session
+:help          print this message
+:quit          exit milk and this prompt (as does end of input)";
+
+const META_COMMANDS: &[&str] = &[":sync", ":streams", ":stats", ":history", ":help", ":quit"];
+
+const PROMPT: &str = "milk> ";
+
+/// Names of the streams in milk's shared memory directory, sorted.
+fn streams() -> io::Result<Vec<String>> {
+    let dir = env::var("MILK_SHM_DIR").unwrap_or_else(|_| "/milk/shm".to_string());
+    let mut names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".im.shm").map(str::to_string)
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Completions of the last word of `line`, sorted and without duplicates:
+/// meta-commands and the commands in `history` for the first word, `images`
+/// for the others.
+fn completions(line: &str, history: &[String], images: &[String]) -> Vec<String> {
+    let word = line.rsplit(' ').next().unwrap_or_default();
+    let mut candidates: Vec<String> = match line.trim_start().contains(' ') {
+        false => META_COMMANDS.iter().map(|meta| meta.to_string())
+            .chain(history.iter().filter_map(|command| command.split_whitespace().next().map(str::to_string)))
+            .collect(),
+        true => images.to_vec(),
+    };
+    candidates.retain(|candidate| candidate.starts_with(word) && candidate != word);
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+/// Longest prefix shared by every one of `words`.
+fn common_prefix(words: &[String]) -> &str {
+    let Some(first) = words.first() else { return "" };
+    let len = words[1..].iter().fold(first.len(), |len, word| {
+        first.char_indices().zip(word.chars())
+            .take_while(|((_, a), b)| a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(len)
+    });
+    &first[..len]
+}
+
+/// The terminal, reading keys one at a time without echoing them while this
+/// lives.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    /// None if stdin isn't a terminal.
+    fn enable() -> Option<Self> {
+        // SAFETY: termios is plain data, filled in by tcgetattr before use
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return None;
+            }
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return None;
+            }
+            let mut raw = original;
+            // Ctrl-C clears the line rather than leaving the terminal raw
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+            Some(Self { original })
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read by tcgetattr in enable()
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Read a line from the terminal in raw mode, with Up and Down recalling
+/// `history` and Tab completing words. None at end of input (Ctrl-D on an
+/// empty line).
+fn edit_line(input: &mut impl Read, history: &[String], images: &[String]) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    // bytes of a character not all read yet
+    let mut partial = Vec::new();
+    // how far back in history Up has gone, 0 being the line being typed
+    let mut recalled = 0;
+    let mut typed = String::new();
+    let redraw = |line: &str| {
+        print!("\r\x1b[K{PROMPT}{line}");
+        io::stdout().flush()
+    };
+    redraw(&line)?;
+    loop {
+        let mut byte = [0];
+        if input.read(&mut byte)? == 0 {
+            return Ok((!line.is_empty()).then_some(line));
+        }
+        match byte[0] {
+            b'\r' | b'\n' => {
+                println!();
+                return Ok(Some(line));
+            }
+            // Ctrl-D
+            4 if line.is_empty() => return Ok(None),
+            // Ctrl-C
+            3 => {
+                println!("^C");
+                line.clear();
+            }
+            // Backspace
+            8 | 127 => {
+                line.pop();
+            }
+            b'\t' => {
+                let candidates = completions(&line, history, images);
+                let word = line.rsplit(' ').next().unwrap_or_default().len();
+                let prefix = common_prefix(&candidates);
+                if prefix.len() > word {
+                    line.push_str(&prefix[word..]);
+                    if candidates.len() == 1 {
+                        line.push(' ');
+                    }
+                } else if candidates.len() > 1 {
+                    println!();
+                    println!("{}", candidates.join("  "));
+                }
+            }
+            // Escape: Up and Down are ESC [ A and ESC [ B
+            27 => {
+                let mut sequence = [0; 2];
+                input.read_exact(&mut sequence)?;
+                let wanted = match sequence {
+                    [b'[', b'A'] => (recalled < history.len()).then_some(recalled + 1),
+                    [b'[', b'B'] => recalled.checked_sub(1),
+                    _ => None,
+                };
+                if let Some(wanted) = wanted {
+                    if recalled == 0 {
+                        typed = mem::take(&mut line);
+                    }
+                    recalled = wanted;
+                    line = match recalled {
+                        0 => mem::take(&mut typed),
+                        n => history[history.len() - n].clone(),
+                    };
+                }
+            }
+            byte if byte >= b' ' => {
+                partial.push(byte);
+                match std::str::from_utf8(&partial) {
+                    Ok(text) => {
+                        line.push_str(text);
+                        partial.clear();
+                    }
+                    // give up on bytes which will never be a character
+                    Err(e) if e.error_len().is_some() => partial.clear(),
+                    Err(_) => {}
+                }
+            }
+            _ => {}
+        }
+        redraw(&line)?;
+    }
+}
+
+/// Print whatever milk has printed since last time.
+fn show_output(milk: &Milk) {
+    if let Some(log) = milk.log_lines() {
+        for line in log.try_iter() {
+            println!("{}", line.text);
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let name = env::args().nth(1);
+    let mut builder = Milk::builder().capture_output(true);
+    if let Some(name) = &name {
+        builder = builder.name(name);
+    }
+    let mut milk = match builder.build() {
+        Ok(milk) => milk,
+        Err(e) => {
+            eprintln!("couldn't start milk: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("milk session {} (:help for help)", milk.label());
+
+    let raw_mode = RawMode::enable();
+    let mut stdin = io::stdin().lock();
+    let mut history = Vec::new();
+    loop {
+        show_output(&milk);
+        let line = match &raw_mode {
+            Some(_) => {
+                let images: Vec<String> = milk.snapshot_state().images().map(str::to_string).collect();
+                edit_line(&mut stdin, &history, &images).transpose()
+            }
+            None => {
+                print!("{PROMPT}");
+                let _ = io::stdout().flush();
+                let mut line = String::new();
+                match stdin.read_line(&mut line) {
+                    Ok(0) => None,
+                    Ok(_) => Some(Ok(line)),
+                    Err(e) => Some(Err(e)),
+                }
+            }
+        };
+        let line = match line {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                eprintln!("couldn't read input: {e}");
+                return ExitCode::FAILURE;
+            }
+            None => break,
+        };
+        let line = line.trim();
+        match line {
+            "" => {}
+            ":quit" | ":q" | "exit" => break,
+            ":help" => println!("{HELP}"),
+            ":history" => {
+                for (i, command) in history.iter().enumerate() {
+                    println!("{:>5}  {command}", i + 1);
+                }
+            }
+            ":sync" => {
+                if let Err(e) = milk.sync() {
+                    eprintln!("couldn't sync: {e}");
+                }
+            }
+            ":streams" => match streams() {
+                Ok(names) => names.iter().for_each(|name| println!("{name}")),
+                Err(e) => eprintln!("couldn't list streams: {e}"),
+            },
+            ":stats" => eprintln!("usage: :stats <image>"),
+            _ if line.starts_with(":stats ") => {
+                let image = line[":stats ".len()..].trim();
+                let outcome = StreamName::new(image)
+                    .and_then(|image| milk.cmd_checked(&format!("imstats {image}")));
+                match outcome {
+                    Ok(outcome) => outcome.output.iter().for_each(|line| println!("{}", line.text)),
+                    Err(e) => eprintln!("couldn't get statistics: {e}"),
+                }
+            }
+            _ if line.starts_with(':') => eprintln!("unknown meta-command {line} (:help for help)"),
+            command => match milk.cmd(command) {
+                Ok(()) => history.push(command.to_string()),
+                Err(e) => eprintln!("couldn't send command: {e}"),
+            },
+        }
+    }
+    drop(raw_mode);
+    println!();
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_prefix, completions};
+
+    #[test]
+    fn completes_words(){
+        let history = ["mk2Dim im1 8 8".to_string(), "mk3Dim cube 8 8 8".to_string(), "listim".to_string()];
+        let images = ["im1".to_string(), "im2".to_string(), "cube".to_string()];
+        assert_eq!(completions(":st", &history, &images), [":stats", ":streams"]);
+        assert_eq!(completions("mk", &history, &images), ["mk2Dim", "mk3Dim"]);
+        assert_eq!(completions("listim", &history, &images), Vec::<String>::new());
+        assert_eq!(completions(":stats i", &history, &images), ["im1", "im2"]);
+        assert_eq!(completions("rmim im1 c", &history, &images), ["cube"]);
+        assert_eq!(common_prefix(&completions(":st", &history, &images)), ":st");
+        assert_eq!(common_prefix(&completions("m", &history, &images)), "mk");
+        assert_eq!(common_prefix(&[]), "");
+    }
+}
@@ -0,0 +1,135 @@
+//! Re-execute a recorded milk session against a fresh milk instance.
+//!
+//! The input is either an audit log written by `milkrs::AuditLog` (only the
+//! command events are replayed) or a plain transcript with one command per
+//! line, where blank lines and lines starting with `#` are skipped.
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+use milkrs::{Milk, AuditRecord};
+
+const USAGE: &str = "\
+usage: milkrs-replay [options] <file>
+
+options:
+    --original-timing   wait between commands as long as in the recording
+                        (audit logs only), instead of replaying as fast as
+                        possible
+    --stop-on-error     stop at the first command which fails to send, or
+                        which failed in the recording
+    --name <name>       process name for the milk session
+    -h, --help          print this message";
+
+/// A command to replay, with its recorded time (if known) and whether it
+/// failed when it was recorded.
+struct Step {
+    command: String,
+    timestamp: Option<f64>,
+    failed: bool,
+}
+
+fn parse_steps(contents: &str) -> Vec<Step> {
+    contents.lines()
+        .filter_map(|line| match AuditRecord::parse(line) {
+            Some(record) if record.event == "command" => Some(Step {
+                command: record.field("command")?.to_string(),
+                timestamp: Some(record.timestamp),
+                failed: record.error.is_some(),
+            }),
+            Some(_) => None,
+            None => {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+                Some(Step {
+                    command: line.to_string(),
+                    timestamp: None,
+                    failed: false,
+                })
+            }
+        })
+        .collect()
+}
+
+fn main() -> ExitCode {
+    let mut original_timing = false;
+    let mut stop_on_error = false;
+    let mut name = None;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--original-timing" => original_timing = true,
+            "--stop-on-error" => stop_on_error = true,
+            "--name" => name = args.next(),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("couldn't read {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let steps = parse_steps(&contents);
+
+    let mut milk = match Milk::new_named(name.as_deref()) {
+        Ok(milk) => milk,
+        Err(e) => {
+            eprintln!("couldn't start milk: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut failed = false;
+    let mut previous: Option<f64> = None;
+    for (i, step) in steps.iter().enumerate() {
+        if original_timing {
+            if let (Some(previous), Some(timestamp)) = (previous, step.timestamp) {
+                thread::sleep(Duration::from_secs_f64((timestamp - previous).max(0.0)));
+            }
+            previous = step.timestamp.or(previous);
+        }
+        if step.failed {
+            eprintln!("step {}: `{}` failed in the recording", i + 1, step.command);
+            failed = true;
+            if stop_on_error {
+                break;
+            }
+        }
+        if let Err(e) = milk.cmd(&step.command) {
+            eprintln!("step {}: couldn't send `{}`: {e}", i + 1, step.command);
+            failed = true;
+            if stop_on_error {
+                break;
+            }
+        }
+    }
+    if let Err(e) = milk.sync() {
+        eprintln!("couldn't sync with milk: {e}");
+        failed = true;
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,235 @@
+//! Keep a set of milk sessions alive without anyone watching.
+//!
+//! Each config file (see `milkrs::config`) describes one session. The
+//! watchdog starts them all, then checks on them periodically: a session whose
+//! milk process has gone away, or which stops answering its heartbeat (if the
+//! config sets one), is restarted (which re-runs its startup commands), unless
+//! it has already been restarted too often recently, in which case it is left
+//! down. Streams named with `--stream` are checked for in
+//! `$MILK_SHM_DIR`, and their disappearance is reported. Every incident is
+//! written with a timestamp to stderr and, with `--log`, appended to a file.
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use milkrs::{Milk, MilkBuilder, StreamName};
+
+const USAGE: &str = "\
+usage: milkrs-watchdog [options] <config>...
+
+options:
+    --interval <ms>       time between checks (default 1000)
+    --max-restarts <n>    give up on a session after restarting it this many
+                          times within the window (default 5)
+    --window <s>          length of the restart window in seconds
+                          (default 300)
+    --stream <name>       report when this stream is missing from
+                          $MILK_SHM_DIR (may be repeated)
+    --log <path>          also append incidents to this file
+    -h, --help            print this message";
+
+/// Set by the SIGINT/SIGTERM handler to ask the watchdog to stop.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Where incidents are reported.
+struct Incidents {
+    log: Option<File>,
+}
+
+impl Incidents {
+    fn report(&mut self, message: &str) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!("{:.3} {message}", now.as_secs_f64());
+        eprintln!("{line}");
+        if let Some(log) = &mut self.log {
+            if let Err(e) = writeln!(log, "{line}") {
+                eprintln!("couldn't write to incident log: {e}");
+                self.log = None;
+            }
+        }
+    }
+}
+
+/// A supervised session and its recent restart history.
+struct Watched {
+    path: String,
+    builder: MilkBuilder,
+    milk: Option<Milk>,
+    restarts: Vec<Instant>,
+    given_up: bool,
+}
+
+impl Watched {
+    fn start(&mut self, incidents: &mut Incidents) {
+        match self.builder.clone().build() {
+            Ok(milk) => {
+                incidents.report(&format!("{}: started session {}", self.path, milk.label()));
+                self.milk = Some(milk);
+            }
+            Err(e) => {
+                incidents.report(&format!("{}: couldn't start session: {e}", self.path));
+                self.milk = None;
+            }
+        }
+    }
+
+    fn is_alive(&mut self) -> bool {
+        self.milk.as_mut().is_some_and(|milk| milk.is_healthy())
+    }
+
+    /// Restart the session if it is down and the restart policy allows it.
+    fn check(&mut self, max_restarts: usize, window: Duration, incidents: &mut Incidents) {
+        if self.given_up || self.is_alive() {
+            return;
+        }
+        if let Some(mut milk) = self.milk.take() {
+            if milk.fifo_closed() {
+                incidents.report(&format!("{}: session {} went away", self.path, milk.label()));
+            } else {
+                incidents.report(&format!("{}: session {} stopped responding", self.path, milk.label()));
+            }
+            // a wedged milk would never act on the exit sent when dropping it
+            let _ = milk.kill(Duration::from_secs(1));
+        }
+        self.restarts.retain(|t| t.elapsed() < window);
+        if self.restarts.len() >= max_restarts {
+            incidents.report(&format!(
+                "{}: restarted {} times in {}s, giving up",
+                self.path, self.restarts.len(), window.as_secs(),
+            ));
+            self.given_up = true;
+            return;
+        }
+        self.restarts.push(Instant::now());
+        self.start(incidents);
+    }
+}
+
+fn shm_dir() -> PathBuf {
+    env::var_os("MILK_SHM_DIR").map_or_else(|| PathBuf::from("/milk/shm"), PathBuf::from)
+}
+
+fn main() -> ExitCode {
+    let mut interval = Duration::from_millis(1000);
+    let mut max_restarts = 5;
+    let mut window = Duration::from_secs(300);
+    let mut streams = Vec::new();
+    let mut log_path = None;
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--interval" => args.next()
+                .and_then(|v| v.parse().ok())
+                .map(|ms| interval = Duration::from_millis(ms)),
+            "--max-restarts" => args.next()
+                .and_then(|v| v.parse().ok())
+                .map(|n| max_restarts = n),
+            "--window" => args.next()
+                .and_then(|v| v.parse().ok())
+                .map(|s| window = Duration::from_secs(s)),
+            "--stream" => match args.next().map(|name| StreamName::new(&name)) {
+                Some(Ok(name)) => {
+                    streams.push(name);
+                    Some(())
+                }
+                Some(Err(e)) => {
+                    eprintln!("{e}");
+                    return ExitCode::FAILURE;
+                }
+                None => None,
+            },
+            "--log" => args.next().map(|path| log_path = Some(path)),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if !arg.starts_with('-') => {
+                paths.push(arg);
+                Some(())
+            }
+            _ => None,
+        };
+        if parsed.is_none() {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    }
+
+    let log = match log_path {
+        Some(path) => match File::options().create(true).append(true).open(&path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("couldn't open {path}: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let mut incidents = Incidents { log };
+
+    let mut watched = Vec::new();
+    for path in paths {
+        match MilkBuilder::from_config(&path) {
+            Ok(builder) => watched.push(Watched {
+                path,
+                builder,
+                milk: None,
+                restarts: Vec::new(),
+                given_up: false,
+            }),
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let handler = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    for session in &mut watched {
+        session.start(&mut incidents);
+    }
+
+    let shm_dir = shm_dir();
+    let mut missing = vec![false; streams.len()];
+    while !STOP.load(Ordering::SeqCst) {
+        for session in &mut watched {
+            session.check(max_restarts, window, &mut incidents);
+        }
+        if watched.iter().all(|session| session.given_up) {
+            incidents.report("all sessions are down, exiting");
+            return ExitCode::FAILURE;
+        }
+        // only report changes, rather than every check while a stream is gone
+        for (name, was_missing) in streams.iter().zip(&mut missing) {
+            let is_missing = fs::metadata(name.shm_path(&shm_dir)).is_err();
+            if is_missing != *was_missing {
+                incidents.report(&format!(
+                    "stream {name} {}",
+                    if is_missing { "is missing" } else { "is back" },
+                ));
+                *was_missing = is_missing;
+            }
+        }
+        thread::sleep(interval);
+    }
+    incidents.report("stopping");
+    ExitCode::SUCCESS
+}
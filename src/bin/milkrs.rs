@@ -0,0 +1,289 @@
+//! Command line front end for managing milk sessions.
+//!
+//! `milkrs exec` spawns milk, runs the commands given as arguments (or, if
+//! there are none, one per line on stdin), waits for them to be executed and
+//! exits, with a nonzero status if anything went wrong. This is a safer
+//! alternative to `echo "..." > fifo` in deployment scripts.
+//!
+//! `milkrs run` brings up the sessions described by one or more config files
+//! (see `milkrs::config`) and runs their startup commands. In setup mode it
+//! then exits, leaving behind whatever the startup commands created in shared
+//! memory; in service mode (`--service`) it stays resident, restarting any
+//! session whose milk process goes away or stops answering its heartbeat,
+//! until it receives SIGINT or SIGTERM. Restarts back off exponentially, and
+//! if a session has to be restarted too often within the restart window,
+//! `milkrs run` gives up and exits with a nonzero status.
+//! Built with the `systemd` feature, service mode also reports readiness,
+//! status and watchdog keep-alives to systemd, so it can be run from a
+//! `Type=notify` unit.
+use std::env;
+use std::io::{self, BufRead};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use milkrs::{Milk, MilkBuilder};
+
+const USAGE: &str = "\
+usage: milkrs <subcommand> [options]
+
+subcommands:
+    exec [--name <name>] [--verbose] [command...]
+        run each command in a fresh milk session, reading commands from
+        stdin (one per line) if none are given, then wait for them to finish
+    run [--service] [--max-restarts <n>] [--window <s>] <config>...
+        start a session for each config file and run its startup commands,
+        then exit, or with --service, keep the sessions running (restarting
+        any that exit) until interrupted, giving up if a session is
+        restarted more than --max-restarts times (default 5) within
+        --window seconds (default 300)
+
+options:
+    -h, --help      print this message";
+
+fn exec(args: Vec<String>) -> ExitCode {
+    let mut name = None;
+    let mut verbose = false;
+    let mut commands = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = args.next(),
+            "-v" | "--verbose" => verbose = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ => commands.push(arg),
+        }
+    }
+    if commands.is_empty() {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => commands.push(line),
+                Err(e) => {
+                    eprintln!("couldn't read commands: {e}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+
+    let mut builder = Milk::builder().verbose(verbose);
+    if let Some(name) = &name {
+        builder = builder.name(name);
+    }
+    let mut milk = match builder.build() {
+        Ok(milk) => milk,
+        Err(e) => {
+            eprintln!("couldn't start milk: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    for command in &commands {
+        if let Err(e) = milk.cmd(command) {
+            eprintln!("couldn't send `{command}`: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if let Err(e) = milk.sync() {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Wait before the first restart of a session, doubling for each further
+/// restart within the restart window.
+const RESTART_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Longest wait before restarting a session.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A session kept running in service mode, and its recent restart history.
+struct Service {
+    path: String,
+    milk: Milk,
+    restarts: Vec<Instant>,
+    /// when to restart the session, once it has been found down
+    restart_at: Option<Instant>,
+}
+
+/// Set by the SIGINT/SIGTERM handler to ask the service loop to stop.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+#[cfg(feature = "systemd")]
+fn sd_notify(state: &str) {
+    if let Err(e) = milkrs::systemd::notify(state) {
+        eprintln!("sd_notify: {e}");
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+fn sd_notify(_state: &str) {}
+
+#[cfg(feature = "systemd")]
+fn watchdog_interval() -> Option<Duration> {
+    milkrs::systemd::watchdog_interval()
+}
+
+#[cfg(not(feature = "systemd"))]
+fn watchdog_interval() -> Option<Duration> {
+    None
+}
+
+fn run(args: Vec<String>) -> ExitCode {
+    let mut service = false;
+    let mut max_restarts = 5;
+    let mut window = Duration::from_secs(300);
+    let mut paths = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--service" => {
+                service = true;
+                Some(())
+            }
+            "--max-restarts" => args.next()
+                .and_then(|v| v.parse().ok())
+                .map(|n| max_restarts = n),
+            "--window" => args.next()
+                .and_then(|v| v.parse().ok())
+                .map(|s| window = Duration::from_secs(s)),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ => {
+                paths.push(arg);
+                Some(())
+            }
+        };
+        if parsed.is_none() {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut builders = Vec::new();
+    for path in &paths {
+        match MilkBuilder::from_config(path) {
+            Ok(builder) => builders.push(builder),
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let mut sessions = Vec::new();
+    for (path, builder) in paths.into_iter().zip(builders) {
+        match builder.build() {
+            Ok(milk) => {
+                eprintln!("{path}: started session {}", milk.label());
+                sessions.push(Service { path, milk, restarts: Vec::new(), restart_at: None });
+            }
+            Err(e) => {
+                eprintln!("{path}: couldn't start session: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if !service {
+        return ExitCode::SUCCESS;
+    }
+
+    let handler = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    sd_notify(&format!("READY=1\nSTATUS=running {} session(s)", sessions.len()));
+    let watchdog = watchdog_interval();
+    let mut last_ping = Instant::now();
+    let mut degraded = false;
+    while !STOP.load(Ordering::SeqCst) {
+        let mut healthy = true;
+        for session in &mut sessions {
+            if session.milk.is_healthy() {
+                continue;
+            }
+            healthy = false;
+            let path = &session.path;
+            let restart_at = match session.restart_at {
+                Some(restart_at) => restart_at,
+                None => {
+                    session.restarts.retain(|t| t.elapsed() < window);
+                    if session.restarts.len() >= max_restarts {
+                        eprintln!(
+                            "{path}: restarted {} times in {}s, giving up",
+                            session.restarts.len(), window.as_secs(),
+                        );
+                        sd_notify(&format!("STATUS={path}: restarted too often, giving up"));
+                        return ExitCode::FAILURE;
+                    }
+                    let backoff = RESTART_BACKOFF
+                        .saturating_mul(1 << session.restarts.len().min(16))
+                        .min(MAX_RESTART_BACKOFF);
+                    eprintln!("{path}: session {} is down, restarting in {backoff:?}", session.milk.label());
+                    sd_notify(&format!("STATUS={path}: session is down, restarting"));
+                    *session.restart_at.insert(Instant::now() + backoff)
+                }
+            };
+            if Instant::now() < restart_at {
+                continue;
+            }
+            session.restart_at = None;
+            session.restarts.push(Instant::now());
+            match session.milk.restart(Duration::from_secs(1)) {
+                Ok(()) => eprintln!("{path}: restarted session {}", session.milk.label()),
+                Err(e) => eprintln!("{path}: couldn't restart session: {e}"),
+            }
+        }
+        if healthy && degraded {
+            sd_notify(&format!("STATUS=running {} session(s)", sessions.len()));
+        }
+        degraded = !healthy;
+        // withhold keep-alives while a session is down or being restarted,
+        // so that systemd restarts the whole unit if it stays down
+        if let Some(interval) = watchdog {
+            if healthy && last_ping.elapsed() >= interval {
+                sd_notify("WATCHDOG=1");
+                last_ping = Instant::now();
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    eprintln!("stopping");
+    sd_notify("STOPPING=1");
+    ExitCode::SUCCESS
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    }
+    match args.remove(0).as_str() {
+        "exec" => exec(args),
+        "run" => run(args),
+        "-h" | "--help" => {
+            println!("{USAGE}");
+            ExitCode::SUCCESS
+        }
+        subcommand => {
+            eprintln!("unknown subcommand `{subcommand}`\n{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{Milk, MilkEvent, Session, RetryPolicy, AuditLog, SharedMilk, StreamName, Result};
+use crate::events::Hooks;
+use crate::log::{self, Alerts, LogSource, RotatingFile};
+use crate::scratch::ScratchDir;
+
+/// Size limit and number of old files kept for `stdout_to()` and
+/// `stderr_to()` unless set with `rotate_output()`, and for `TelemetryWriter`.
+pub(crate) const DEFAULT_ROTATION: (u64, usize) = (10_000_000, 5);
+
+/// Shortest wait for milk to open its fifo, even on an attempt made at the
+/// retry policy's deadline.
+const MIN_FIFO_WAIT: Duration = Duration::from_millis(250);
+
+/// Number of sessions built so far by this program, used for session ids
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Configures and starts a Milk session. Obtain one with `Milk::builder()`.
+#[derive(Debug, Clone, Default)]
+pub struct MilkBuilder {
+    name: Option<String>,
+    label: Option<String>,
+    binary: Option<String>,
+    env: Vec<(String, String)>,
+    args: Vec<String>,
+    capture_output: bool,
+    stdout_to: Option<PathBuf>,
+    stderr_to: Option<PathBuf>,
+    rotation: Option<(u64, usize)>,
+    alert_patterns: Vec<String>,
+    pub(crate) unhealthy_on_alert: bool,
+    cpus: Option<Vec<usize>>,
+    dry_run: bool,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) audit: Option<AuditLog>,
+    pub(crate) atomic_batches: bool,
+    pub(crate) verbose: bool,
+    pub(crate) buffered: bool,
+    pub(crate) startup_cmds: Vec<String>,
+    pub(crate) name_prefix: String,
+    pub(crate) hooks: Hooks,
+    pub(crate) heartbeat: Option<(Duration, Duration)>,
+    pub(crate) write_deadline: Option<Duration>,
+    pub(crate) check_timeout: Option<Duration>,
+}
+
+impl MilkBuilder {
+    /// Builder with default settings, equivalent to `Milk::builder()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process name given to milk (passed as `milk -n <name>`).
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Label identifying the session in audit records, verbose output and
+    /// error messages, e.g., to tell apart the sessions of a program running
+    /// several. Defaults to the process name (see `name()`), or failing that
+    /// the session id.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let milk = Milk::builder().label("dm-offload").build().unwrap();
+    /// assert_eq!(milk.label(), "dm-offload");
+    /// ```
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Builder configured by the session profile in the TOML file at `path`
+    /// (see the `config` module docs for the format). The result can be
+    /// customised further before calling `build()`.
+    pub fn from_config(path: impl AsRef<Path>) -> Result<Self> {
+        crate::config::builder_from_file(path.as_ref())
+    }
+
+    /// Path of the milk executable to run, instead of `milk` on the PATH.
+    pub fn binary(mut self, binary: &str) -> Self {
+        self.binary = Some(binary.to_string());
+        self
+    }
+
+    /// Set an environment variable for milk, e.g., `MILK_SHM_DIR`.
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Prefix applied to the names of images and streams made through the
+    /// session's helpers (`Milk::mk2d()`, `Milk::load_fits()` and
+    /// `Milk::stream_name()`), so that several experiments or parallel tests
+    /// on one machine can't trample each other's streams. Commands passed to
+    /// `cmd()` are sent as they are.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::builder().name_prefix("testA_").build().unwrap();
+    /// let im = milk.mk2d("im1", 64, 64)?;
+    /// assert_eq!(im.name(), "testA_im1");
+    /// assert_eq!(milk.stream_name("wfs")?.as_str(), "testA_wfs");
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn name_prefix(mut self, prefix: &str) -> Self {
+        self.name_prefix = prefix.to_string();
+        self
+    }
+
+    /// Pass an extra command line option to milk, e.g., to turn on its
+    /// logging. Options are passed after the ones milkrs needs.
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Capture what milk prints to stdout and stderr, to be read line by
+    /// line from `Milk::log_lines()`, instead of discarding it. Lines are
+    /// kept until they are read.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// use std::time::Duration;
+    /// let mut milk = Milk::builder().capture_output(true).build().unwrap();
+    /// milk.cmd("listim")?;
+    /// milk.sync()?;
+    /// let lines = milk.log_lines().unwrap();
+    /// while let Ok(line) = lines.recv_timeout(Duration::from_millis(100)) {
+    ///     println!("{:?} {}", line.source, line.text);
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn capture_output(mut self, capture_output: bool) -> Self {
+        self.capture_output = capture_output;
+        self
+    }
+
+    /// Append what milk prints to stdout to the file at `path`, rotating it
+    /// once it grows past a size limit (see `rotate_output()`). Works
+    /// alongside `capture_output()`.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let milk = Milk::builder()
+    ///     .stdout_to("/tmp/milk-aol0.log")
+    ///     .stderr_to("/tmp/milk-aol0.err")
+    ///     .rotate_output(1_000_000, 3)  // milk-aol0.log.1 to .3 kept
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn stdout_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stdout_to = Some(path.into());
+        self
+    }
+
+    /// Append what milk prints to stderr to the file at `path`, rotating it
+    /// like `stdout_to()`.
+    pub fn stderr_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stderr_to = Some(path.into());
+        self
+    }
+
+    /// Rotate the files given to `stdout_to()` and `stderr_to()` before they
+    /// grow past `max_bytes`, keeping `keep` old files named `<path>.1`
+    /// (newest) to `<path>.<keep>`. Defaults to 10 MB and 5 old files.
+    pub fn rotate_output(mut self, max_bytes: u64, keep: usize) -> Self {
+        self.rotation = Some((max_bytes, keep));
+        self
+    }
+
+    /// Watch milk's stderr for lines containing `pattern` (matched exactly,
+    /// including case), e.g., `"ERROR"` or `"segfault"`, and emit a
+    /// `MilkEvent::Alert` to the session's hooks for each one - so failures
+    /// milk only mentions in passing reach the program. Can be given several
+    /// times.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::{Milk, MilkEvent};
+    /// let milk = Milk::builder()
+    ///     .alert_on("ERROR")
+    ///     .alert_on("Segmentation fault")
+    ///     .unhealthy_on_alert(true)
+    ///     .on_event(|event: &MilkEvent| {
+    ///         if let MilkEvent::Alert { line, .. } = event {
+    ///             eprintln!("milk reported: {line}");
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn alert_on(mut self, pattern: &str) -> Self {
+        self.alert_patterns.push(pattern.to_string());
+        self
+    }
+
+    /// Whether an alert (see `alert_on()`) makes `Milk::is_healthy()` report
+    /// the session unhealthy from then on, e.g., so a supervisor restarts it.
+    /// Off by default.
+    pub fn unhealthy_on_alert(mut self, unhealthy_on_alert: bool) -> Self {
+        self.unhealthy_on_alert = unhealthy_on_alert;
+        self
+    }
+
+    /// Restrict milk to running on the given CPUs.
+    pub fn cpus(mut self, cpus: &[usize]) -> Self {
+        self.cpus = Some(cpus.to_vec());
+        self
+    }
+
+    /// In dry-run mode no milk process or fifo is created, and commands are
+    /// recorded instead of sent - see `Milk::recorded_commands()`. Useful for
+    /// inspecting what a program would send without needing milk installed.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Retry policy applied when spawning milk and when writing commands to
+    /// the fifo. By default nothing is retried.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// In buffered mode, commands passed to `cmd()` and friends are queued
+    /// rather than sent straight away, and only reach milk as one batch on
+    /// `flush()`, `sync()`, a tracked command, or closing the session. Until
+    /// then they can be inspected with `Milk::pending()` or dropped with
+    /// `Milk::clear_pending()`.
+    pub fn buffered(mut self, buffered: bool) -> Self {
+        self.buffered = buffered;
+        self
+    }
+
+    /// Print every command to stderr as it is sent, prefixed with the session
+    /// label (see `label()`), to follow what a program is asking milk to do.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Send milk a sentinel every `interval` while `Milk::is_healthy()` is
+    /// being polled, and report the session unhealthy if one goes unanswered
+    /// for longer than `threshold` - catching a milk which is still running
+    /// but no longer reading commands.
+    pub fn heartbeat(mut self, interval: Duration, threshold: Duration) -> Self {
+        self.heartbeat = Some((interval, threshold));
+        self
+    }
+
+    /// Bound how long a command can block the calling thread: the fifo is
+    /// made non-blocking, and if milk hasn't made room in it within
+    /// `deadline` the command fails with an `io::Error` of kind `WouldBlock`
+    /// (and is not sent, although a long batch from `cmds()` may have been
+    /// partly sent) instead of stalling, e.g., a real-time loop until milk
+    /// catches up. Commands longer than `PIPE_BUF` are rejected in this
+    /// mode, as they could otherwise be left half written. Failed writes are
+    /// not retried, whatever the retry policy.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// use std::io;
+    /// use std::time::Duration;
+    /// let mut milk = Milk::builder().write_deadline(Duration::from_micros(200)).build().unwrap();
+    /// if let Err(e) = milk.cmd("mk2Dim im1 64 64") {
+    ///     match e.downcast_ref::<io::Error>() {
+    ///         Some(e) if e.kind() == io::ErrorKind::WouldBlock => eprintln!("milk is busy, skipped"),
+    ///         _ => return Err(e),
+    ///     }
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn write_deadline(mut self, deadline: Duration) -> Self {
+        self.write_deadline = Some(deadline);
+        self
+    }
+
+    /// How long `cmd_checked()` (and the first use of a command with
+    /// `cmd_from()`) waits for milk to finish a command before failing with
+    /// an `io::Error` of kind `TimedOut`, e.g., if milk never echoes the
+    /// marker following the command. Defaults to 10 seconds.
+    pub fn check_timeout(mut self, timeout: Duration) -> Self {
+        self.check_timeout = Some(timeout);
+        self
+    }
+
+    /// Require every write to the fifo to be atomic: `cmds()` fails rather
+    /// than splitting a batch over several writes, and `cmd()` fails for a
+    /// command longer than `PIPE_BUF`. Only needed when several processes
+    /// write to the same fifo and batches must not be interleaved.
+    pub fn atomic_batches(mut self, atomic_batches: bool) -> Self {
+        self.atomic_batches = atomic_batches;
+        self
+    }
+
+    /// Commands to run as soon as milk starts. `build()` only returns once
+    /// milk has executed all of them, so the session starts in a known state.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let milk = Milk::builder()
+    ///     .startup_cmds(&["mk2Dim im1 64 64", "imcp2shm im1 ims1"])
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn startup_cmds(mut self, commands: &[&str]) -> Self {
+        self.startup_cmds = commands.iter().map(|command| command.to_string()).collect();
+        self
+    }
+
+    /// Record a structured audit trail of the session to `audit`.
+    pub fn audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Register a hook to be called with each lifecycle event of the session,
+    /// starting with its spawn. Sessions built from clones of this builder
+    /// share the hook.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::{Milk, MilkEvent};
+    /// let milk = Milk::builder()
+    ///     .on_event(|event: &MilkEvent| {
+    ///         if let MilkEvent::Spawned { pid } = event {
+    ///             eprintln!("milk started as {pid:?}");
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn on_event(mut self, hook: impl FnMut(&MilkEvent) + Send + 'static) -> Self {
+        self.hooks.add(hook);
+        self
+    }
+
+    /// Start the configured Milk session.
+    pub fn build(self) -> Result<Milk> {
+        if !self.name_prefix.is_empty() {
+            StreamName::new(&format!("{}x", self.name_prefix))
+                .map_err(|_| format!("name prefix {:?} can't start a stream name", self.name_prefix))?;
+        }
+        let id = format!("{}-{}", process::id(), SESSIONS.fetch_add(1, Ordering::Relaxed));
+        let label = self.label_for(&id);
+        let session = self.spawn(&id);
+        if let Some(audit) = &self.audit {
+            match &session {
+                Ok(Session::Live { milk_process, fifo_name, .. }) => audit.record(
+                    &id, &label, "spawn",
+                    &[("pid", &milk_process.id().to_string()), ("fifo", fifo_name)],
+                    None,
+                ),
+                Ok(Session::DryRun { .. }) => audit.record(&id, &label, "spawn", &[("dry_run", "true")], None),
+                Err(e) => audit.record(&id, &label, "spawn", &[], Some(&e.to_string())),
+            }
+        }
+        let session = session.map_err(|e| format!("milk session {label}: {e}"))?;
+        let mut milk = Milk::from_session(session, self, id, label);
+        milk.config.hooks.emit(MilkEvent::Spawned { pid: milk.pid() });
+        milk.start_up()?;
+        Ok(milk)
+    }
+
+    /// Start the configured session, run `commands` in it, then exit it and
+    /// return milk's exit status - see `Milk::exec_once()`.
+    pub fn exec_once(self, commands: &[&str]) -> Result<ExitStatus> {
+        let mut milk = self.build()?;
+        milk.cmds(commands.to_vec())?;
+        // dry-run sessions have no process, and nothing that could fail
+        Ok(milk.close()?.unwrap_or(ExitStatus::from_raw(0)))
+    }
+
+    /// Start the configured session on a thread of its own, returning a
+    /// handle which can be cloned and used from any thread through `&self` -
+    /// see `SharedMilk`. Interceptors and other per-session callbacks can be
+    /// added with `SharedMilk::with()`.
+    pub fn build_shared(self) -> Result<SharedMilk> {
+        SharedMilk::start(self)
+    }
+
+    /// The label of a session with id `id` built from this builder.
+    fn label_for(&self, id: &str) -> String {
+        self.label.clone().or_else(|| self.name.clone()).unwrap_or_else(|| id.to_string())
+    }
+
+    /// Create the fifo and start milk reading from it.
+    pub(crate) fn spawn(&self, id: &str) -> Result<Session> {
+        if self.dry_run {
+            return Ok(Session::DryRun { commands: Vec::new() });
+        }
+
+        // the session id is unique among running programs, and the timestamp
+        // guards against a stale directory left behind by a reused pid
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.subsec_nanos())
+            .unwrap_or_default();
+        let scratch = ScratchDir::create(&format!("{id}.{nanos:09}"))?;
+        let fifo_name = scratch.path().join("fifo").to_string_lossy().into_owned();
+        
+        make_fifo(&fifo_name)?;
+
+        let (max_bytes, keep) = self.rotation.unwrap_or(DEFAULT_ROTATION);
+        let open = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|path| RotatingFile::open(path, max_bytes, keep)
+                    .map_err(|e| format!("couldn't open {}: {e}", path.display())))
+                .transpose()
+        };
+        let stdout_file = open(&self.stdout_to)?;
+        let stderr_file = open(&self.stderr_to)?;
+        
+        // retry milk exiting before it reads the fifo (e.g., while the shm
+        // directory is missing) as well as failing to spawn at all
+        let started = Instant::now();
+        let (mut milk_process, fifo_pipe) = self.retry.run(|| {
+            let wait = self.retry.remaining(started).map(|remaining| remaining.max(MIN_FIFO_WAIT));
+            self.launch(&fifo_name, wait)
+        }, |_| true).map_err(|e| format!("Failed to spawn milk process: {e}"))?;
+        if self.write_deadline.is_none() {
+            if let Err(e) = crate::set_nonblocking(&fifo_pipe, false) {
+                let _ = milk_process.kill();
+                let _ = milk_process.wait();
+                return Err(e.into());
+            }
+        }
+        
+        let (sender, log) = match self.capture_output {
+            true => {
+                let (sender, receiver) = mpsc::channel();
+                (Some(sender), Some(receiver))
+            }
+            false => (None, None),
+        };
+        let alerted = Arc::new(AtomicBool::new(false));
+        let alerts = (!self.alert_patterns.is_empty()).then(|| Alerts {
+            patterns: self.alert_patterns.clone(),
+            hooks: self.hooks.clone(),
+            raised: alerted.clone(),
+        });
+        let mut tails = Vec::new();
+        if let Some(stdout) = milk_process.stdout.take() {
+            tails.push(log::tail(stdout, LogSource::Stdout, sender.clone(), stdout_file, None));
+        }
+        if let Some(stderr) = milk_process.stderr.take() {
+            tails.push(log::tail(stderr, LogSource::Stderr, sender, stderr_file, alerts));
+        }
+
+        Ok(Session::Live {
+            milk_process,
+            fifo_pipe,
+            fifo_name,
+            scratch,
+            log,
+            tails,
+            alerted,
+        })
+    }
+
+    /// Start milk and open its fifo for writing once milk has opened it for
+    /// reading. Fails if milk exits first, or (given `wait`) isn't reading
+    /// within `wait`. The fifo is returned non-blocking.
+    fn launch(&self, fifo_name: &str, wait: Option<Duration>) -> io::Result<(Child, File)> {
+        let mut command = Command::new(self.binary.as_deref().unwrap_or("milk"));
+        command
+            .arg("-f")
+            .arg("-F")
+            .arg(fifo_name)
+            .args(match &self.name {
+                Some(name) => vec!["-n",name],
+                None => vec![]
+            })
+            .args(&self.args)
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .stdout(self.output(&self.stdout_to))
+            .stderr(match self.alert_patterns.is_empty() {
+                true => self.output(&self.stderr_to),
+                false => Stdio::piped(),
+            })
+            .stdin(Stdio::null());
+        if let Some(cpus) = self.cpus.clone() {
+            // SAFETY: only calls the async-signal-safe sched_setaffinity
+            // on memory owned by the closure
+            unsafe {
+                command.pre_exec(move || set_affinity(&cpus));
+            }
+        }
+        let mut milk_process = command.spawn()?;
+        let started = Instant::now();
+        let failed = |milk_process: &mut Child, e: io::Error| {
+            // don't leave a zombie milk behind if we can't talk to it
+            let _ = milk_process.kill();
+            let _ = milk_process.wait();
+            Err(e)
+        };
+        loop {
+            // a non-blocking open for writing fails with ENXIO until there
+            // is a reader, rather than blocking forever if milk never comes
+            match File::options().append(true).custom_flags(libc::O_NONBLOCK).open(fifo_name) {
+                Ok(fifo_pipe) => return Ok((milk_process, fifo_pipe)),
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {}
+                Err(e) => return failed(&mut milk_process, e),
+            }
+            match milk_process.try_wait() {
+                Ok(Some(status)) => return Err(io::Error::other(format!("milk exited during startup ({status})"))),
+                Ok(None) => {}
+                Err(e) => return failed(&mut milk_process, e),
+            }
+            if wait.is_some_and(|wait| started.elapsed() >= wait) {
+                return failed(&mut milk_process, io::Error::new(
+                    io::ErrorKind::TimedOut, "milk didn't open its fifo in time",
+                ));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn output(&self, file: &Option<PathBuf>) -> Stdio {
+        if self.capture_output || file.is_some() { Stdio::piped() } else { Stdio::null() }
+    }
+}
+
+/// Pin the calling process to `cpus`.
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t is plain data for which all zeroes is the empty set,
+    // and CPU_SET is only given in-range CPU numbers
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus.iter().filter(|&&cpu| cpu < libc::CPU_SETSIZE as usize) {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Create a fifo at `path`, readable and writable only by this user since
+/// anything written to it is executed by milk. The error keeps the kind of
+/// the underlying errno (e.g., `AlreadyExists`, `PermissionDenied`).
+fn make_fifo(path: &str) -> io::Result<()> {
+    let c_path = CString::new(path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: c_path is a valid nul-terminated string for the duration of the call
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(e.kind(), format!("couldn't create fifo {path}: {e}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::make_fifo;
+    use crate::{Milk, RetryPolicy};
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::process;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn makes_private_fifos(){
+        let path = "/tmp/.milkrs_make_fifo_test";
+        let _ = fs::remove_file(path);
+        make_fifo(path).expect("couldn't make fifo");
+        let metadata = fs::metadata(path).unwrap();
+        assert!(metadata.file_type().is_fifo());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        let e = make_fifo(path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn retries_milk_exiting_at_startup(){
+        // a milk which exits straight away on its first two launches
+        let count = format!("/tmp/milkrs_flaky_milk_{}.count", process::id());
+        let script = format!("/tmp/milkrs_flaky_milk_{}.sh", process::id());
+        fs::write(&script, format!(
+            "#!/bin/sh\necho x >> {count}\n[ $(wc -l < {count}) -gt 2 ] || exit 1\nexec milk \"$@\"\n"
+        )).expect("couldn't write script");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).expect("couldn't chmod script");
+        let _ = fs::remove_file(&count);
+        let retry = RetryPolicy::new(5, Duration::from_millis(10)).deadline(Duration::from_secs(5));
+        let milk = Milk::builder().binary(&script).retry(retry).build().expect("Failed to start milk");
+        assert_eq!(fs::read_to_string(&count).unwrap().lines().count(), 3);
+        milk.close().expect("couldn't close");
+
+        // and one which never starts
+        fs::write(&script, "#!/bin/sh\nexit 1\n").expect("couldn't write script");
+        let retry = RetryPolicy::new(1000, Duration::from_millis(10)).deadline(Duration::from_millis(300));
+        let start = Instant::now();
+        let e = Milk::builder().binary(&script).retry(retry).build().err().expect("milk shouldn't start");
+        assert!(e.to_string().contains("exited during startup"), "{e}");
+        assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+        fs::remove_file(&script).unwrap();
+        fs::remove_file(&count).unwrap();
+    }
+}
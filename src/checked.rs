@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::log::{LogLevel, LogLine, MilkLogEvent};
+
+/// What happened when milk ran a command sent with `Milk::cmd_checked()`:
+/// the lines it printed while running it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdOutcome {
+    /// the command as it was passed to `cmd_checked()`
+    pub command: String,
+    /// everything milk printed while running the command
+    pub output: Vec<LogLine>,
+}
+
+impl CmdOutcome {
+    /// The output, classified.
+    pub fn events(&self) -> Vec<MilkLogEvent> {
+        self.output.iter().map(LogLine::event).collect()
+    }
+
+    /// The output lines which report errors.
+    pub fn errors(&self) -> Vec<MilkLogEvent> {
+        self.events().into_iter().filter(|event| event.level() == LogLevel::Error).collect()
+    }
+
+    /// Whether the command ran without milk reporting any errors.
+    pub fn succeeded(&self) -> bool {
+        self.errors().is_empty()
+    }
+}
+
+/// Error from `Milk::cmd_from()` when milk doesn't know a command because
+/// the module providing it isn't part of the local milk build (or isn't
+/// loaded). Pick it out with `downcast_ref()`.
+///
+/// # Example
+/// ```
+/// use milkrs::{Milk, ModuleUnavailable};
+/// let mut milk = Milk::builder().capture_output(true).build().unwrap();
+/// match milk.cmd_from("image_gen", "mkgauss psf 64 64 4") {
+///     Err(e) if e.downcast_ref::<ModuleUnavailable>().is_some() => {
+///         eprintln!("no image_gen here, loading psf from file instead");
+///         milk.cmd("loadfits \"/tmp/psf.fits\" psf")?;
+///     }
+///     result => result?,
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleUnavailable {
+    /// the module which should provide the command
+    pub module: String,
+    /// the command milk didn't know
+    pub command: String,
+}
+
+impl fmt::Display for ModuleUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "milk has no {} command: module {} is unavailable", self.command, self.module)
+    }
+}
+
+impl Error for ModuleUnavailable {}
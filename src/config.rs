@@ -0,0 +1,422 @@
+//! Session profiles loaded from TOML files.
+//!
+//! A profile describes one milk session:
+//! ```toml
+//! name = "aol0"                   # milk -n <name>
+//! label = "aol0"                  # in logs and errors, defaults to name
+//! binary = "/usr/local/bin/milk"  # defaults to milk on the PATH
+//! cpuset = "2-3,6"                # CPUs milk may run on
+//! startup_cmds = [
+//!     "mload milkimageformat",
+//! ]
+//! streams = ["aol0_wfsim"]        # loaded with readshmim at startup
+//! audit_log = "/var/log/milk/aol0.jsonl"
+//! stdout_to = "/var/log/milk/aol0.out"  # rotated at 10 MB
+//! stderr_to = "/var/log/milk/aol0.err"
+//! alert_on = ["ERROR", "segfault"]   # patterns to watch stderr for
+//! unhealthy_on_alert = false
+//! atomic_batches = false
+//! verbose = false                 # echo commands to stderr
+//! buffered = false                # queue commands until flushed
+//! name_prefix = "aol0_"           # prepended to image and stream names
+//!
+//! [env]
+//! MILK_SHM_DIR = "/milk/shm"
+//!
+//! [retry]
+//! max_attempts = 5
+//! backoff_ms = 100
+//! max_backoff_ms = 2000
+//! jitter = 0.5                    # shorten sleeps by up to half at random
+//! deadline_ms = 30000             # give up after this long regardless
+//!
+//! [heartbeat]
+//! interval_ms = 1000
+//! threshold_ms = 5000             # defaults to five intervals
+//! ```
+//!
+//! Only the subset of TOML needed for this is understood: strings, integers,
+//! floats, booleans and arrays of those, tables, and comments. Unknown keys
+//! are errors, so typos don't silently fall back to defaults.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{MilkBuilder, AuditLog, RetryPolicy, StreamName, Result};
+
+/// A TOML value.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "boolean",
+            Value::Array(_) => "array",
+        }
+    }
+}
+
+/// Parse TOML into a flat map from dotted key (e.g., `env.MILK_SHM_DIR`) to
+/// value.
+pub(crate) fn parse(text: &str) -> Result<BTreeMap<String, Value>> {
+    let mut values = BTreeMap::new();
+    let mut table = String::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let mut line = strip_comment(line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            let name = line.strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+                .map(str::trim)
+                .filter(|name| !name.is_empty() && !name.starts_with('['))
+                .ok_or_else(|| format!("line {}: bad table header `{line}`", i + 1))?;
+            table = format!("{name}.");
+            continue;
+        }
+        // arrays may continue over several lines
+        while !brackets_balanced(&line) {
+            let (_, next) = lines.next()
+                .ok_or_else(|| format!("line {}: unterminated array", i + 1))?;
+            line.push(' ');
+            line.push_str(strip_comment(next).trim());
+        }
+        let (key, value) = line.split_once('=')
+            .ok_or_else(|| format!("line {}: expected `key = value`", i + 1))?;
+        let key = format!("{table}{}", key.trim().trim_matches('"'));
+        let mut chars = value.trim().chars().peekable();
+        let value = parse_value(&mut chars)
+            .ok_or_else(|| format!("line {}: bad value for `{key}`", i + 1))?;
+        if chars.any(|c| !c.is_whitespace()) {
+            return Err(format!("line {}: trailing characters after `{key}`", i + 1).into());
+        }
+        if values.insert(key.clone(), value).is_some() {
+            return Err(format!("line {}: `{key}` defined twice", i + 1).into());
+        }
+    }
+    Ok(values)
+}
+
+/// `line` with any comment (a `#` outside of a string) removed.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Whether every `[` after the `=` in `line` (outside strings) is closed.
+fn brackets_balanced(line: &str) -> bool {
+    let Some((_, value)) = line.split_once('=') else {
+        return true;
+    };
+    let mut depth = 0i32;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth <= 0
+}
+
+fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<Value> {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    match *chars.peek()? {
+        '"' => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => return Some(Value::String(s)),
+                    '\\' => match chars.next()? {
+                        'n' => s.push('\n'),
+                        't' => s.push('\t'),
+                        'r' => s.push('\r'),
+                        '"' => s.push('"'),
+                        '\\' => s.push('\\'),
+                        _ => return None,
+                    },
+                    c => s.push(c),
+                }
+            }
+        }
+        '\'' => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next()? {
+                    '\'' => return Some(Value::String(s)),
+                    c => s.push(c),
+                }
+            }
+        }
+        '[' => {
+            chars.next();
+            let mut items = Vec::new();
+            loop {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                if chars.next_if_eq(&']').is_some() {
+                    return Some(Value::Array(items));
+                }
+                items.push(parse_value(chars)?);
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                match chars.next()? {
+                    ',' => {}
+                    ']' => return Some(Value::Array(items)),
+                    _ => return None,
+                }
+            }
+        }
+        _ => {
+            let mut token = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ',' && *c != ']') {
+                token.push(c);
+            }
+            let token = token.replace('_', "");
+            match token.as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => token.parse().map(Value::Integer).ok()
+                    .or_else(|| token.parse().map(Value::Float).ok()),
+            }
+        }
+    }
+}
+
+/// Parse a cpuset like `"0-3,6"` into the listed CPU numbers.
+pub(crate) fn parse_cpuset(cpuset: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in cpuset.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let bad = || format!("bad cpuset `{cpuset}`");
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.trim().parse().map_err(|_| bad())?;
+                let last: usize = last.trim().parse().map_err(|_| bad())?;
+                if first > last {
+                    return Err(bad().into());
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.parse().map_err(|_| bad())?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Builder configured by the profile in `text`.
+pub(crate) fn builder_from_str(text: &str) -> Result<MilkBuilder> {
+    let values = parse(text)?;
+    let mut builder = MilkBuilder::new();
+    let mut retry = (None, None);
+    let mut retry_extras = (None, None, None);
+    let mut heartbeat = (None, None);
+    let mut startup_cmds = Vec::new();
+    let mut streams = Vec::new();
+    let mut alert_on = Vec::new();
+    for (key, value) in values {
+        let mismatch = |expected: &str| format!("`{key}` should be a {expected}, not a {}", value.type_name());
+        match (key.as_str(), &value) {
+            ("name", Value::String(name)) => builder = builder.name(name),
+            ("label", Value::String(label)) => builder = builder.label(label),
+            ("binary", Value::String(binary)) => builder = builder.binary(binary),
+            ("name_prefix", Value::String(prefix)) => builder = builder.name_prefix(prefix),
+            ("stdout_to", Value::String(path)) => builder = builder.stdout_to(path),
+            ("stderr_to", Value::String(path)) => builder = builder.stderr_to(path),
+            ("cpuset", Value::String(cpuset)) => builder = builder.cpus(&parse_cpuset(cpuset)?),
+            ("startup_cmds" | "streams" | "alert_on", Value::Array(items)) => {
+                let list = match key.as_str() {
+                    "streams" => &mut streams,
+                    "alert_on" => &mut alert_on,
+                    _ => &mut startup_cmds,
+                };
+                for item in items {
+                    match item {
+                        Value::String(item) => list.push(item.clone()),
+                        _ => return Err(mismatch("array of strings").into()),
+                    }
+                }
+            }
+            ("audit_log", Value::String(path)) => {
+                let audit = AuditLog::to_file(path)
+                    .map_err(|e| format!("couldn't open audit log {path}: {e}"))?;
+                builder = builder.audit_log(audit);
+            }
+            ("atomic_batches", Value::Bool(atomic)) => builder = builder.atomic_batches(*atomic),
+            ("dry_run", Value::Bool(dry_run)) => builder = builder.dry_run(*dry_run),
+            ("verbose", Value::Bool(verbose)) => builder = builder.verbose(*verbose),
+            ("buffered", Value::Bool(buffered)) => builder = builder.buffered(*buffered),
+            ("unhealthy_on_alert", Value::Bool(unhealthy)) => builder = builder.unhealthy_on_alert(*unhealthy),
+            ("retry.max_attempts", Value::Integer(n)) => retry.0 = Some(*n),
+            ("retry.backoff_ms", Value::Integer(ms)) => retry.1 = Some(*ms),
+            ("retry.max_backoff_ms", Value::Integer(ms)) => retry_extras.0 = Some(*ms),
+            ("retry.jitter", Value::Float(jitter)) => retry_extras.1 = Some(*jitter),
+            ("retry.jitter", Value::Integer(jitter)) => retry_extras.1 = Some(*jitter as f64),
+            ("retry.deadline_ms", Value::Integer(ms)) => retry_extras.2 = Some(*ms),
+            ("heartbeat.interval_ms", Value::Integer(ms)) => heartbeat.0 = Some(*ms),
+            ("heartbeat.threshold_ms", Value::Integer(ms)) => heartbeat.1 = Some(*ms),
+            (key, Value::String(v)) if key.starts_with("env.") => builder = builder.env(&key[4..], v),
+            ("name" | "label" | "binary" | "name_prefix" | "cpuset" | "audit_log" | "stdout_to" | "stderr_to", _) => return Err(mismatch("string").into()),
+            ("atomic_batches" | "dry_run" | "verbose" | "buffered" | "unhealthy_on_alert", _) => return Err(mismatch("boolean").into()),
+            ("startup_cmds" | "streams" | "alert_on", _) => return Err(mismatch("array of strings").into()),
+            ("retry.max_attempts" | "retry.backoff_ms" | "retry.max_backoff_ms" | "retry.deadline_ms", _) => {
+                return Err(mismatch("integer").into())
+            }
+            ("retry.jitter", _) => return Err(mismatch("number").into()),
+            ("heartbeat.interval_ms" | "heartbeat.threshold_ms", _) => return Err(mismatch("integer").into()),
+            (key, _) if key.starts_with("env.") => return Err(mismatch("string").into()),
+            (key, _) => return Err(format!("unknown setting `{key}`").into()),
+        }
+    }
+    for pattern in &alert_on {
+        builder = builder.alert_on(pattern);
+    }
+    for stream in &streams {
+        StreamName::new(stream).map_err(|e| format!("`streams`: {e}"))?;
+    }
+    // streams are loaded after the startup commands, which may load the
+    // modules needed to read them
+    startup_cmds.extend(streams.iter().map(|stream| format!("readshmim {stream}")));
+    if !startup_cmds.is_empty() {
+        builder = builder.startup_cmds(&startup_cmds.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+    if retry != (None, None) || retry_extras != (None, None, None) {
+        // with a deadline, attempts are unlimited unless capped explicitly
+        let default_attempts = if retry_extras.2.is_some() { u32::MAX as i64 } else { 1 };
+        let max_attempts = retry.0.unwrap_or(default_attempts).clamp(1, u32::MAX as i64) as u32;
+        let backoff = Duration::from_millis(retry.1.unwrap_or(0).max(0) as u64);
+        let mut policy = RetryPolicy::new(max_attempts, backoff);
+        if let Some(ms) = retry_extras.0 {
+            policy = policy.max_backoff(Duration::from_millis(ms.max(0) as u64));
+        }
+        if let Some(jitter) = retry_extras.1 {
+            policy = policy.jitter(jitter);
+        }
+        if let Some(ms) = retry_extras.2 {
+            policy = policy.deadline(Duration::from_millis(ms.max(0) as u64));
+        }
+        builder = builder.retry(policy);
+    }
+    match heartbeat {
+        (None, None) => {}
+        (Some(interval), threshold) => {
+            let interval = interval.max(1) as u64;
+            let threshold = threshold.map_or(5 * interval, |ms| ms.max(1) as u64);
+            builder = builder.heartbeat(Duration::from_millis(interval), Duration::from_millis(threshold));
+        }
+        (None, Some(_)) => return Err("`heartbeat.threshold_ms` needs `heartbeat.interval_ms`".into()),
+    }
+    Ok(builder)
+}
+
+/// Builder configured by the profile in the file at `path`.
+pub(crate) fn builder_from_file(path: &Path) -> Result<MilkBuilder> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("couldn't read config {}: {e}", path.display()))?;
+    builder_from_str(&text).map_err(|e| format!("{}: {e}", path.display()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_cpuset, builder_from_str, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn parses_toml_subset(){
+        let values = parse(r#"
+            # a comment
+            name = "aol0"   # trailing comment
+            path = 'C:\no\escapes'
+            count = 1_000
+            gain = -0.5
+            enabled = true
+            cmds = [
+                "mload milkimageformat",  # comment in array
+                "readshmim \"a#b\"",
+            ]
+            [env]
+            MILK_SHM_DIR = "/milk/shm"
+        "#).unwrap();
+        assert_eq!(values["name"], Value::String("aol0".into()));
+        assert_eq!(values["path"], Value::String(r"C:\no\escapes".into()));
+        assert_eq!(values["count"], Value::Integer(1000));
+        assert_eq!(values["gain"], Value::Float(-0.5));
+        assert_eq!(values["enabled"], Value::Bool(true));
+        assert_eq!(values["cmds"], Value::Array(vec![
+            Value::String("mload milkimageformat".into()),
+            Value::String("readshmim \"a#b\"".into()),
+        ]));
+        assert_eq!(values["env.MILK_SHM_DIR"], Value::String("/milk/shm".into()));
+        assert!(parse("a = 1\na = 2").is_err());
+        assert!(parse("a = [1, 2").is_err());
+        assert!(parse("a = \"unterminated").is_err());
+    }
+
+    #[test]
+    fn parses_cpusets(){
+        assert_eq!(parse_cpuset("0-3, 6").unwrap(), vec![0, 1, 2, 3, 6]);
+        assert!(parse_cpuset("3-1").is_err());
+        assert!(parse_cpuset("x").is_err());
+    }
+
+    #[test]
+    fn configures_builder(){
+        let builder = builder_from_str(r#"
+            name = "aol0"
+            dry_run = true
+            streams = ["aol0_wfsim"]
+            startup_cmds = ["mload milkimageformat"]
+            [env]
+            MILK_SHM_DIR = "/tmp"
+        "#).unwrap();
+        let milk = builder.build().unwrap();
+        assert_eq!(
+            milk.recorded_commands().unwrap(),
+            ["mload milkimageformat", "readshmim aol0_wfsim"],
+        );
+        assert!(builder_from_str("nmae = \"typo\"").is_err());
+        assert!(builder_from_str("name = 3").is_err());
+        assert!(builder_from_str("streams = [\"aol0 wfsim\"]").is_err());
+        let builder = builder_from_str("[heartbeat]\ninterval_ms = 100").unwrap();
+        assert_eq!(builder.heartbeat, Some((Duration::from_millis(100), Duration::from_millis(500))));
+        assert!(builder_from_str("[heartbeat]\nthreshold_ms = 100").is_err());
+        assert_eq!(builder_from_str("name_prefix = \"testA_\"").unwrap().name_prefix, "testA_");
+        assert!(builder_from_str("alert_on = [\"ERROR\"]\nunhealthy_on_alert = true").unwrap().unhealthy_on_alert);
+        assert!(builder_from_str("alert_on = \"ERROR\"").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// A milk process reading commands from a fifo, found by `discover_sessions()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredSession {
+    /// process id of milk
+    pub pid: u32,
+    /// the fifo milk reads commands from (`-F`)
+    pub fifo: PathBuf,
+    /// the process name given to milk (`-n`), if any
+    pub name: Option<String>,
+    /// the program as it was invoked (the first word of its command line)
+    pub program: String,
+}
+
+/// Find every milk session on the system started in fifo mode (`-f -F
+/// <fifo>`), whether by this program, another milkrs program, or by hand -
+/// e.g., to attach tooling to them or clean up after crashed supervisors.
+/// Only programs called `milk` are recognised, so sessions started with a
+/// differently named `MilkBuilder::binary()` are missed. Processes whose
+/// command lines can't be read (e.g., other users' processes on a hardened
+/// system) are skipped.
+///
+/// # Example
+/// ```
+/// use milkrs::{Milk, discover_sessions};
+/// let milk = Milk::new().unwrap();
+/// let sessions = discover_sessions()?;
+/// assert!(sessions.iter().any(|session| Some(session.pid) == milk.pid()));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn discover_sessions() -> io::Result<Vec<DiscoveredSession>> {
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse().ok()) else {
+            continue;
+        };
+        // the process may have exited since listing /proc
+        let Ok(cmdline) = fs::read(entry.path().join("cmdline")) else {
+            continue;
+        };
+        if let Some(session) = parse_cmdline(pid, &cmdline) {
+            sessions.push(session);
+        }
+    }
+    sessions.sort_by_key(|session| session.pid);
+    Ok(sessions)
+}
+
+/// Recognise a milk fifo-mode invocation from the NUL-separated contents of
+/// `/proc/<pid>/cmdline`.
+fn parse_cmdline(pid: u32, cmdline: &[u8]) -> Option<DiscoveredSession> {
+    let args: Vec<String> = cmdline.split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    // milk may be a script, in which case the interpreter comes first
+    let milk = args.iter().take(2).position(|arg| {
+        arg.rsplit('/').next().is_some_and(|base| base == "milk")
+    })?;
+    let options = &args[milk + 1..];
+    if !options.iter().any(|arg| arg == "-f") {
+        return None;
+    }
+    let value_of = |flag: &str| {
+        let i = options.iter().position(|arg| arg == flag)?;
+        options.get(i + 1).cloned()
+    };
+    Some(DiscoveredSession {
+        pid,
+        fifo: PathBuf::from(value_of("-F")?),
+        name: value_of("-n"),
+        program: args[0].clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cmdline;
+    use std::path::Path;
+
+    #[test]
+    fn recognises_milk_fifo_sessions(){
+        let session = parse_cmdline(42, b"milk\0-f\0-F\0/tmp/.fifo.1-0.5\0-n\0aol0\0").unwrap();
+        assert_eq!(session.pid, 42);
+        assert_eq!(session.fifo, Path::new("/tmp/.fifo.1-0.5"));
+        assert_eq!(session.name.as_deref(), Some("aol0"));
+        let session = parse_cmdline(1, b"python3\0/usr/local/bin/milk\0-f\0-F\0/tmp/f\0").unwrap();
+        assert_eq!(session.program, "python3");
+        assert_eq!(session.name, None);
+        assert!(parse_cmdline(1, b"milk\0").is_none());
+        assert!(parse_cmdline(1, b"milk\0-F\0/tmp/f\0").is_none());
+        assert!(parse_cmdline(1, b"vim\0-f\0-F\0/tmp/f\0").is_none());
+        assert!(parse_cmdline(1, b"milk-fpsCTRL\0-f\0-F\0/tmp/f\0").is_none());
+    }
+}
@@ -0,0 +1,221 @@
+//! Conversion of float frames into RGB8 images for display, shared by
+//! anything which needs to show a frame to a human.
+//!
+//! # Example
+//! ```
+//! use milkrs::display::{to_rgb8, Colormap, Stretch};
+//! let frame: Vec<f32> = (0..64 * 64).map(|i| i as f32).collect();
+//! let rgb = to_rgb8(&frame, Colormap::Viridis, Stretch::Asinh);
+//! assert_eq!(rgb.len(), 3 * 64 * 64);
+//! ```
+
+/// Mapping from normalised intensity in [0, 1] to colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colormap {
+    Gray,
+    Viridis,
+    Inferno,
+}
+
+/// matplotlib's viridis sampled at 0.0, 0.1, ..., 1.0
+const VIRIDIS: [[u8; 3]; 11] = [
+    [0x44, 0x01, 0x54], [0x48, 0x24, 0x75], [0x41, 0x44, 0x87], [0x35, 0x5f, 0x8d],
+    [0x2a, 0x78, 0x8e], [0x21, 0x91, 0x8c], [0x22, 0xa8, 0x84], [0x44, 0xbf, 0x70],
+    [0x7a, 0xd1, 0x51], [0xbd, 0xdf, 0x26], [0xfd, 0xe7, 0x25],
+];
+
+/// matplotlib's inferno sampled at 0.0, 0.1, ..., 1.0
+const INFERNO: [[u8; 3]; 11] = [
+    [0x00, 0x00, 0x04], [0x16, 0x0b, 0x39], [0x42, 0x0a, 0x68], [0x6a, 0x17, 0x6e],
+    [0x93, 0x26, 0x67], [0xbc, 0x37, 0x54], [0xdd, 0x51, 0x3a], [0xf3, 0x78, 0x19],
+    [0xfc, 0xa5, 0x0a], [0xf6, 0xd7, 0x46], [0xfc, 0xff, 0xa4],
+];
+
+impl Colormap {
+    /// Colour for normalised intensity `t`, clamped to [0, 1]. NaN maps to
+    /// black.
+    pub fn rgb(self, t: f32) -> [u8; 3] {
+        if t.is_nan() {
+            return [0, 0, 0];
+        }
+        let t = t.clamp(0.0, 1.0);
+        let table = match self {
+            Colormap::Gray => {
+                let v = (t * 255.0).round() as u8;
+                return [v, v, v];
+            }
+            Colormap::Viridis => &VIRIDIS,
+            Colormap::Inferno => &INFERNO,
+        };
+        let x = t * (table.len() - 1) as f32;
+        let i = (x.floor() as usize).min(table.len() - 2);
+        let frac = x - i as f32;
+        let mut rgb = [0; 3];
+        for (c, out) in rgb.iter_mut().enumerate() {
+            let (a, b) = (table[i][c] as f32, table[i + 1][c] as f32);
+            *out = (a + (b - a) * frac).round() as u8;
+        }
+        rgb
+    }
+}
+
+/// How frame values are mapped onto the colormap. `Linear`, `Log` and
+/// `Asinh` span the finite range of the frame, while `ZScale` is a linear
+/// stretch between IRAF-style zscale limits, which ignore outliers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stretch {
+    Linear,
+    Log,
+    Asinh,
+    ZScale,
+}
+
+impl Stretch {
+    /// The (low, high) values which map onto the ends of the colormap.
+    pub fn limits(self, data: &[f32]) -> (f32, f32) {
+        match self {
+            Stretch::ZScale => zscale(data),
+            _ => data.iter()
+                .filter(|v| v.is_finite())
+                .fold(None, |range, &v| match range {
+                    None => Some((v, v)),
+                    Some((lo, hi)) => Some((f32::min(lo, v), f32::max(hi, v))),
+                })
+                .unwrap_or((0.0, 1.0)),
+        }
+    }
+
+    /// Map `t`, the value already scaled linearly to [0, 1], through this
+    /// stretch.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Stretch::Linear | Stretch::ZScale => t,
+            Stretch::Log => (1.0 + 1000.0 * t).log10() / 1001f32.log10(),
+            Stretch::Asinh => (10.0 * t).asinh() / 10f32.asinh(),
+        }
+    }
+}
+
+/// Render `data` as packed RGB8 (3 bytes per value, in the same order).
+pub fn to_rgb8(data: &[f32], colormap: Colormap, stretch: Stretch) -> Vec<u8> {
+    let (lo, hi) = stretch.limits(data);
+    let span = if hi > lo { hi - lo } else { 1.0 };
+    data.iter()
+        .flat_map(|&v| colormap.rgb(stretch.apply((v - lo) / span)))
+        .collect()
+}
+
+/// Display limits from the IRAF zscale algorithm: fit a line to the sorted
+/// (sampled) pixel values with iterative sigma clipping, and take the range
+/// around the median that the line spans, scaled by the contrast.
+pub fn zscale(data: &[f32]) -> (f32, f32) {
+    const MAX_SAMPLES: usize = 1000;
+    const CONTRAST: f32 = 0.25;
+    const MAX_REJECT: f32 = 0.5;
+    const MIN_PIXELS: usize = 5;
+    const KREJ: f32 = 2.5;
+    const MAX_ITERATIONS: usize = 5;
+
+    let step = (data.len() / MAX_SAMPLES).max(1);
+    let mut samples: Vec<f32> = data.iter()
+        .step_by(step)
+        .copied()
+        .filter(|v| v.is_finite())
+        .collect();
+    if samples.is_empty() {
+        return (0.0, 1.0);
+    }
+    samples.sort_by(f32::total_cmp);
+    let n = samples.len();
+    let (min, max) = (samples[0], samples[n - 1]);
+    let median = samples[n / 2];
+    if n < MIN_PIXELS {
+        return (min, max);
+    }
+
+    // least squares fit of sample value against (centred) index, rejecting
+    // outliers until the fit settles
+    let centre = (n - 1) as f32 / 2.0;
+    let mut keep = vec![true; n];
+    let mut slope = 0.0;
+    for _ in 0..MAX_ITERATIONS {
+        let kept = keep.iter().filter(|k| **k).count();
+        if kept < MIN_PIXELS.max(((1.0 - MAX_REJECT) * n as f32) as usize) {
+            break;
+        }
+        let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+        for (i, &y) in samples.iter().enumerate().filter(|(i, _)| keep[*i]) {
+            let x = i as f32 - centre;
+            sx += x;
+            sy += y;
+            sxx += x * x;
+            sxy += x * y;
+        }
+        let k = kept as f32;
+        let denominator = k * sxx - sx * sx;
+        if denominator == 0.0 {
+            break;
+        }
+        slope = (k * sxy - sx * sy) / denominator;
+        let intercept = (sy - slope * sx) / k;
+        let residual = |i: usize| samples[i] - (intercept + slope * (i as f32 - centre));
+        let sigma = ((0..n).filter(|i| keep[*i]).map(|i| residual(i).powi(2)).sum::<f32>() / k).sqrt();
+        let mut rejected = false;
+        for (i, keep) in keep.iter_mut().enumerate() {
+            if *keep && residual(i).abs() > KREJ * sigma {
+                *keep = false;
+                rejected = true;
+            }
+        }
+        if !rejected {
+            break;
+        }
+    }
+
+    let slope = slope / CONTRAST;
+    let lo = (median - centre * slope).max(min);
+    let hi = (median + centre * slope).min(max);
+    if hi > lo { (lo, hi) } else { (min, max) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colormaps_span_their_tables(){
+        assert_eq!(Colormap::Gray.rgb(0.0), [0, 0, 0]);
+        assert_eq!(Colormap::Gray.rgb(1.0), [255, 255, 255]);
+        assert_eq!(Colormap::Viridis.rgb(-1.0), VIRIDIS[0]);
+        assert_eq!(Colormap::Viridis.rgb(0.5), VIRIDIS[5]);
+        assert_eq!(Colormap::Inferno.rgb(2.0), INFERNO[10]);
+        assert_eq!(Colormap::Inferno.rgb(f32::NAN), [0, 0, 0]);
+    }
+
+    #[test]
+    fn stretches_preserve_endpoints(){
+        for stretch in [Stretch::Linear, Stretch::Log, Stretch::Asinh, Stretch::ZScale] {
+            assert_eq!(stretch.apply(0.0), 0.0);
+            assert!((stretch.apply(1.0) - 1.0).abs() < 1e-6);
+            assert!(stretch.apply(0.5) >= 0.5);
+        }
+    }
+
+    #[test]
+    fn zscale_ignores_outliers(){
+        let mut data: Vec<f32> = (0..1000).map(|i| (i % 100) as f32).collect();
+        data[17] = 1e6;
+        data[42] = -1e6;
+        let (lo, hi) = zscale(&data);
+        assert!((-1e3..=0.0).contains(&lo), "lo = {lo}");
+        assert!((99.0..=1e3).contains(&hi), "hi = {hi}");
+        assert_eq!(Stretch::Linear.limits(&data), (-1e6, 1e6));
+    }
+
+    #[test]
+    fn renders_rgb8(){
+        let rgb = to_rgb8(&[0.0, f32::NAN, 10.0], Colormap::Gray, Stretch::Linear);
+        assert_eq!(rgb, [0, 0, 0, 0, 0, 0, 255, 255, 255]);
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+
+/// Something that happened during the life of a Milk session, as passed to
+/// hooks registered with `MilkBuilder::on_event()` or `Milk::on_event()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MilkEvent<'a> {
+    /// milk has been started, with this process id (None for dry-run sessions)
+    Spawned { pid: Option<u32> },
+    /// a command has been written to the fifo, or failed to be if `ok` is false
+    Command { command: &'a str, ok: bool },
+    /// `sync()` has seen milk catch up with every command sent so far
+    Synced,
+    /// the session has been closed, and milk exited with `status` (None for
+    /// dry-run sessions)
+    Exited { status: Option<ExitStatus> },
+    /// milk printed `line` to stderr, which contains `pattern`, one of those
+    /// given to `MilkBuilder::alert_on()`. Emitted from a background thread.
+    Alert { pattern: &'a str, line: &'a str },
+}
+
+type Hook = Arc<Mutex<dyn FnMut(&MilkEvent) + Send>>;
+
+/// The hooks registered on a session. Cloned sets share their hooks, so a
+/// builder can be reused for several sessions.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Vec<Hook>);
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hooks({})", self.0.len())
+    }
+}
+
+impl Hooks {
+    pub(crate) fn add(&mut self, hook: impl FnMut(&MilkEvent) + Send + 'static) {
+        self.0.push(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Pass `event` to every hook, in the order they were added. A hook which
+    /// panicked earlier is still called.
+    pub(crate) fn emit(&self, event: MilkEvent) {
+        for hook in &self.0 {
+            let mut hook = hook.lock().unwrap_or_else(|e| e.into_inner());
+            hook(&event);
+        }
+    }
+}
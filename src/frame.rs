@@ -0,0 +1,458 @@
+//! In-memory 2D frames and the reductions commonly applied to them before
+//! display or transport.
+use crate::Result;
+
+/// A 2D frame of float pixels, stored row-major.
+///
+/// # Example
+/// ```
+/// use milkrs::frame::Frame;
+/// let frame = Frame::new(4, 2, (0..8).map(|i| i as f32).collect()).unwrap();
+/// assert_eq!(frame.get(1, 1), Some(5.0));
+/// assert_eq!(frame.bin2x2().data(), &[2.5, 4.5]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Frame {
+    /// Frame of `width` x `height` pixels, given row by row in `data`.
+    pub fn new(width: usize, height: usize, data: Vec<f32>) -> Result<Self> {
+        if data.len() != width * height {
+            return Err(format!(
+                "{width}x{height} frame needs {} pixels, got {}", width * height, data.len()
+            ).into());
+        }
+        Ok(Self { width, height, data })
+    }
+
+    /// Frame of `width` x `height` pixels converted from raw camera values as
+    /// `(raw - offset) * scale`, given row by row in `raw`.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::frame::Frame;
+    /// let raw: [u16; 4] = [100, 102, 110, 1100];
+    /// let frame = Frame::from_raw_scaled(2, 2, &raw, 0.5, 100.0).unwrap();
+    /// assert_eq!(frame.data(), &[0.0, 1.0, 5.0, 500.0]);
+    /// ```
+    pub fn from_raw_scaled<T: Copy + Into<f64>>(
+        width: usize,
+        height: usize,
+        raw: &[T],
+        scale: f64,
+        offset: f64,
+    ) -> Result<Self> {
+        if raw.len() != width * height {
+            return Err(format!(
+                "{width}x{height} frame needs {} pixels, got {}", width * height, raw.len()
+            ).into());
+        }
+        let data = raw.iter().map(|&v| ((v.into() - offset) * scale) as f32).collect();
+        Ok(Self { width, height, data })
+    }
+
+    /// Frame of `width` x `height` zeros.
+    pub fn zeros(width: usize, height: usize) -> Self {
+        Self { width, height, data: vec![0.0; width * height] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Pixels, row by row.
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// Mutable pixels, row by row.
+    pub fn data_mut(&mut self) -> &mut [f32] {
+        &mut self.data
+    }
+
+    pub fn into_data(self) -> Vec<f32> {
+        self.data
+    }
+
+    /// Pixel at column `x`, row `y`, or None if out of bounds.
+    pub fn get(&self, x: usize, y: usize) -> Option<f32> {
+        if x < self.width && y < self.height {
+            Some(self.data[y * self.width + x])
+        } else {
+            None
+        }
+    }
+
+    /// Pixels of row `y`.
+    pub fn row(&self, y: usize) -> &[f32] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    /// CRC-32 (IEEE) of the frame's pixels as little-endian f32 bytes, for
+    /// verifying frames end-to-end after transport or logging. The
+    /// dimensions are not included.
+    pub fn crc32(&self) -> u32 {
+        let bytes = self.data.iter().flat_map(|v| v.to_le_bytes());
+        !bytes.fold(!0, crc32_update)
+    }
+
+    /// Average each 2x2 block of pixels. A trailing odd row or column is
+    /// dropped.
+    pub fn bin2x2(&self) -> Frame {
+        let (width, height) = (self.width / 2, self.height / 2);
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let (top, bottom) = (self.row(2 * y), self.row(2 * y + 1));
+            data.extend(
+                top.chunks_exact(2)
+                    .zip(bottom.chunks_exact(2))
+                    .map(|(t, b)| 0.25 * (t[0] + t[1] + b[0] + b[1]))
+            );
+        }
+        Frame { width, height, data }
+    }
+
+    /// Downsample to `width` x `height` by averaging. Each output pixel covers
+    /// an equal share of the input, to the nearest whole pixel, so the sizes
+    /// needn't divide evenly.
+    pub fn bin_to(&self, width: usize, height: usize) -> Result<Frame> {
+        if width == 0 || height == 0 || width > self.width || height > self.height {
+            return Err(format!(
+                "can't bin {}x{} frame to {width}x{height}", self.width, self.height
+            ).into());
+        }
+        let columns: Vec<usize> = (0..=width).map(|x| x * self.width / width).collect();
+        let mut data = Vec::with_capacity(width * height);
+        let mut sums = vec![0.0; width];
+        for y in 0..height {
+            let (y0, y1) = (y * self.height / height, (y + 1) * self.height / height);
+            sums.iter_mut().for_each(|sum| *sum = 0.0);
+            for row in y0..y1 {
+                let row = self.row(row);
+                for (sum, bounds) in sums.iter_mut().zip(columns.windows(2)) {
+                    *sum += row[bounds[0]..bounds[1]].iter().sum::<f32>();
+                }
+            }
+            data.extend(sums.iter().zip(columns.windows(2)).map(|(sum, bounds)| {
+                sum / ((bounds[1] - bounds[0]) * (y1 - y0)) as f32
+            }));
+        }
+        Ok(Frame { width, height, data })
+    }
+
+    /// Keep every `stride`-th pixel in each direction, starting from (0, 0).
+    /// Cheaper than binning, at the cost of aliasing.
+    pub fn decimate(&self, stride: usize) -> Frame {
+        let stride = stride.max(1);
+        let width = self.width.div_ceil(stride);
+        let height = self.height.div_ceil(stride);
+        let data = (0..self.height).step_by(stride)
+            .flat_map(|y| self.row(y).iter().step_by(stride).copied())
+            .collect();
+        Frame { width, height, data }
+    }
+}
+
+/// Which pixels of a frame hold data, e.g., those inside a pupil or behind
+/// working actuators. Masked-out pixels are ignored by
+/// `compare_frames_masked()`, `FrameHistogram::add_masked()` and
+/// `modes::project_masked()`.
+///
+/// # Example
+/// ```
+/// use milkrs::frame::{Frame, Mask};
+/// let pupil = Mask::circular(4);
+/// assert_eq!(pupil.count(), 12);
+/// let frame = Frame::new(4, 4, (0..16).map(|i| i as f32).collect()).unwrap();
+/// assert_eq!(pupil.select(&frame).unwrap().len(), 12);
+/// assert_eq!(pupil.apply(&frame, f32::NAN).unwrap().get(0, 0).map(f32::is_nan), Some(true));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask {
+    width: usize,
+    height: usize,
+    bits: Vec<bool>,
+}
+
+impl Mask {
+    /// Mask of `width` x `height` pixels, given row by row in `bits`, with
+    /// `true` for pixels holding data.
+    pub fn new(width: usize, height: usize, bits: Vec<bool>) -> Result<Self> {
+        if bits.len() != width * height {
+            return Err(format!(
+                "{width}x{height} mask needs {} pixels, got {}", width * height, bits.len()
+            ).into());
+        }
+        Ok(Self { width, height, bits })
+    }
+
+    /// Mask of the pixels of `frame` which are finite and non-zero, as in a
+    /// pupil or actuator map loaded from a FITS file.
+    pub fn from_frame(frame: &Frame) -> Self {
+        let bits = frame.data.iter().map(|v| v.is_finite() && *v != 0.0).collect();
+        Self { width: frame.width, height: frame.height, bits }
+    }
+
+    /// Mask of the pixels of a `size` x `size` frame whose centres lie within
+    /// the circle inscribed in it.
+    pub fn circular(size: usize) -> Self {
+        let half = size as f64 / 2.0;
+        let bits = (0..size * size).map(|i| {
+            let (x, y) = ((i % size) as f64 + 0.5 - half, (i / size) as f64 + 0.5 - half);
+            x.hypot(y) <= half
+        }).collect();
+        Self { width: size, height: size, bits }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Whether pixel (x, y) holds data. False if out of bounds.
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.bits[y * self.width + x]
+    }
+
+    /// Pixels holding data, row by row.
+    pub fn bits(&self) -> &[bool] {
+        &self.bits
+    }
+
+    /// Number of pixels holding data.
+    pub fn count(&self) -> usize {
+        self.bits.iter().filter(|&&bit| bit).count()
+    }
+
+    /// The pixels of `frame` holding data, in row order.
+    pub fn select(&self, frame: &Frame) -> Result<Vec<f32>> {
+        self.check(frame)?;
+        Ok(frame.data.iter().zip(&self.bits).filter(|(_, &bit)| bit).map(|(&v, _)| v).collect())
+    }
+
+    /// Copy of `frame` with masked-out pixels set to `fill`.
+    pub fn apply(&self, frame: &Frame, fill: f32) -> Result<Frame> {
+        self.check(frame)?;
+        let data = frame.data.iter().zip(&self.bits).map(|(&v, &bit)| if bit { v } else { fill }).collect();
+        Ok(Frame { width: frame.width, height: frame.height, data })
+    }
+
+    pub(crate) fn check(&self, frame: &Frame) -> Result<()> {
+        if (self.width, self.height) != (frame.width, frame.height) {
+            return Err(format!(
+                "can't use {}x{} mask with {}x{} frame", self.width, self.height, frame.width, frame.height
+            ).into());
+        }
+        Ok(())
+    }
+}
+
+/// Differences between two frames, from `compare_frames()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffReport {
+    /// largest absolute difference between corresponding pixels
+    pub max_abs_diff: f32,
+    /// root mean square of the differences
+    pub rms_diff: f32,
+    /// number of pixels differing by more than the tolerance
+    pub differing: usize,
+    /// (x, y, difference) of the pixels differing most, largest first
+    pub worst: Vec<(usize, usize, f32)>,
+}
+
+impl DiffReport {
+    /// Whether every pixel was within tolerance.
+    pub fn matches(&self) -> bool {
+        self.differing == 0
+    }
+}
+
+/// Number of worst pixel locations kept in a `DiffReport`.
+const WORST_PIXELS: usize = 10;
+
+/// Compare two frames of equal size pixel by pixel, counting pixels as
+/// differing if `|a - b| > tolerance`. Pixels which are NaN in both frames
+/// match, while a NaN in only one counts as an infinite difference.
+///
+/// # Example
+/// ```
+/// use milkrs::frame::{Frame, compare_frames};
+/// let a = Frame::new(2, 1, vec![1.0, 2.0]).unwrap();
+/// let b = Frame::new(2, 1, vec![1.0, 2.5]).unwrap();
+/// let report = compare_frames(&a, &b, 0.1).unwrap();
+/// assert_eq!(report.differing, 1);
+/// assert_eq!(report.worst, vec![(1, 0, -0.5)]);
+/// ```
+pub fn compare_frames(a: &Frame, b: &Frame, tolerance: f32) -> Result<DiffReport> {
+    compare(a, b, None, tolerance)
+}
+
+/// `compare_frames()` over only the pixels in `mask`.
+pub fn compare_frames_masked(a: &Frame, b: &Frame, mask: &Mask, tolerance: f32) -> Result<DiffReport> {
+    mask.check(a)?;
+    compare(a, b, Some(mask), tolerance)
+}
+
+fn compare(a: &Frame, b: &Frame, mask: Option<&Mask>, tolerance: f32) -> Result<DiffReport> {
+    if (a.width, a.height) != (b.width, b.height) {
+        return Err(format!(
+            "can't compare {}x{} frame with {}x{} frame", a.width, a.height, b.width, b.height
+        ).into());
+    }
+    let mut max_abs_diff: f32 = 0.0;
+    let mut sum_sq = 0.0;
+    let mut differing = 0;
+    let mut compared = 0;
+    let mut worst: Vec<(usize, usize, f32)> = Vec::with_capacity(WORST_PIXELS + 1);
+    for (i, (&va, &vb)) in a.data.iter().zip(&b.data).enumerate() {
+        if mask.is_some_and(|mask| !mask.bits[i]) {
+            continue;
+        }
+        compared += 1;
+        let diff = match (va.is_nan(), vb.is_nan()) {
+            (true, true) => 0.0,
+            // equal infinities would otherwise differ by NaN
+            _ if va == vb => 0.0,
+            (false, false) => va - vb,
+            _ => f32::INFINITY,
+        };
+        max_abs_diff = max_abs_diff.max(diff.abs());
+        sum_sq += diff as f64 * diff as f64;
+        if diff.abs() > tolerance {
+            differing += 1;
+            if worst.len() < WORST_PIXELS || diff.abs() > worst[WORST_PIXELS - 1].2.abs() {
+                let at = worst.partition_point(|w| w.2.abs() >= diff.abs());
+                worst.insert(at, (i % a.width, i / a.width, diff));
+                worst.truncate(WORST_PIXELS);
+            }
+        }
+    }
+    let rms_diff = if compared == 0 { 0.0 } else { (sum_sq / compared as f64).sqrt() as f32 };
+    Ok(DiffReport { max_abs_diff, rms_diff, differing, worst })
+}
+
+/// Table for the reflected CRC-32 polynomial 0xedb88320, built at compile time.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, Mask, compare_frames, compare_frames_masked, crc32_update};
+
+    fn ramp(width: usize, height: usize) -> Frame {
+        Frame::new(width, height, (0..width * height).map(|i| i as f32).collect()).unwrap()
+    }
+
+    #[test]
+    fn rejects_wrong_size(){
+        assert!(Frame::new(3, 3, vec![0.0; 8]).is_err());
+    }
+
+    #[test]
+    fn converts_raw_values(){
+        let frame = Frame::from_raw_scaled(3, 1, &[-2i32, 0, u16::MAX as i32], 2.0, -1.0).unwrap();
+        assert_eq!(frame.data(), &[-2.0, 2.0, 131072.0]);
+        assert!(Frame::from_raw_scaled(2, 2, &[0u8; 3], 1.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn bin2x2_drops_odd_edges(){
+        let binned = ramp(5, 3).bin2x2();
+        assert_eq!((binned.width(), binned.height()), (2, 1));
+        assert_eq!(binned.data(), &[3.0, 5.0]);
+    }
+
+    #[test]
+    fn bin_to_matches_bin2x2(){
+        let frame = ramp(8, 6);
+        assert_eq!(frame.bin_to(4, 3).unwrap(), frame.bin2x2());
+        let uneven = frame.bin_to(3, 1).unwrap();
+        // columns 0..2, 2..5 and 5..8 averaged over all rows
+        assert_eq!(uneven.data(), &[20.5, 23.0, 26.0]);
+        assert!(frame.bin_to(9, 1).is_err());
+    }
+
+    #[test]
+    fn decimate_keeps_strided_pixels(){
+        let decimated = ramp(5, 5).decimate(2);
+        assert_eq!((decimated.width(), decimated.height()), (3, 3));
+        assert_eq!(decimated.data(), &[0.0, 2.0, 4.0, 10.0, 12.0, 14.0, 20.0, 22.0, 24.0]);
+    }
+
+    #[test]
+    fn crc32_matches_reference(){
+        // standard CRC-32 check value
+        let check = !b"123456789".iter().fold(!0, |crc, &byte| crc32_update(crc, byte));
+        assert_eq!(check, 0xcbf4_3926);
+        let mut frame = ramp(4, 4);
+        let before = frame.crc32();
+        frame.data_mut()[5] += 1e-3;
+        assert_ne!(frame.crc32(), before);
+    }
+
+    #[test]
+    fn compare_reports_differences(){
+        let mut a = ramp(4, 4);
+        a.data_mut()[1] = f32::INFINITY;
+        let mut b = a.clone();
+        b.data_mut()[6] += 3.0;
+        b.data_mut()[9] -= 1.0;
+        b.data_mut()[12] = f32::NAN;
+        let report = compare_frames(&a, &b, 0.5).unwrap();
+        assert_eq!(report.differing, 3);
+        assert_eq!(report.max_abs_diff, f32::INFINITY);
+        assert_eq!(report.worst, vec![(0, 3, f32::INFINITY), (2, 1, -3.0), (1, 2, 1.0)]);
+        assert!(!report.matches());
+        assert!(compare_frames(&a, &a, 0.0).unwrap().matches());
+        assert!(compare_frames(&a, &ramp(4, 3), 0.0).is_err());
+    }
+
+    #[test]
+    fn masks_ignore_dead_pixels(){
+        let a = ramp(4, 4);
+        let mut b = a.clone();
+        b.data_mut()[0] = 100.0;
+        b.data_mut()[5] += 1.0;
+        let pupil = Mask::circular(4);
+        assert!(!pupil.contains(0, 0) && pupil.contains(1, 0) && !pupil.contains(4, 0));
+        let report = compare_frames_masked(&a, &b, &pupil, 0.5).unwrap();
+        assert_eq!((report.differing, report.max_abs_diff), (1, 1.0));
+        assert!((report.rms_diff - (1.0f32 / 12.0).sqrt()).abs() < 1e-6);
+        assert!(compare_frames_masked(&a, &b, &Mask::circular(3), 0.5).is_err());
+
+        let map = Frame::new(2, 2, vec![1.0, 0.0, f32::NAN, -2.0]).unwrap();
+        let mask = Mask::from_frame(&map);
+        assert_eq!(mask.bits(), &[true, false, false, true]);
+        assert_eq!(mask.select(&ramp(2, 2)).unwrap(), [0.0, 3.0]);
+        assert_eq!(mask.apply(&ramp(2, 2), -1.0).unwrap().data(), &[0.0, -1.0, -1.0, 3.0]);
+        assert!(Mask::new(2, 2, vec![true; 3]).is_err());
+    }
+}
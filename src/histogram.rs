@@ -0,0 +1,200 @@
+//! Histograms of pixel values, for autoscaling and saturation monitoring.
+use crate::frame::{Frame, Mask};
+use crate::Result;
+
+/// Fixed-range histogram of pixel values. It can be built in one shot from a
+/// frame with `FrameHistogram::of()`, or used as a running accumulator by
+/// calling `add()` with each new frame, in which case `percentile()` gives a
+/// streaming estimate whose resolution is the bin width.
+///
+/// # Example
+/// ```
+/// use milkrs::frame::Frame;
+/// use milkrs::histogram::FrameHistogram;
+/// let mut hist = FrameHistogram::new(1000, 0.0, 65535.0);
+/// let frame = Frame::new(2, 2, vec![10.0, 20.0, 30.0, 65535.0]).unwrap();
+/// hist.add(&frame);
+/// assert_eq!(hist.total(), 4);
+/// assert_eq!(hist.count_at_least(65000.0), 1);  // saturated pixels
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameHistogram {
+    lo: f32,
+    hi: f32,
+    counts: Vec<u64>,
+    below: u64,
+    above: u64,
+    nan: u64,
+}
+
+impl FrameHistogram {
+    /// Empty histogram of `bins` equal bins spanning [lo, hi].
+    pub fn new(bins: usize, lo: f32, hi: f32) -> Self {
+        let hi = if hi > lo { hi } else { lo + 1.0 };
+        Self {
+            lo,
+            hi,
+            counts: vec![0; bins.max(1)],
+            below: 0,
+            above: 0,
+            nan: 0,
+        }
+    }
+
+    /// Histogram of a single frame, with bins spanning its finite range.
+    pub fn of(frame: &Frame, bins: usize) -> Self {
+        let (lo, hi) = frame.data().iter()
+            .filter(|v| v.is_finite())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+        let mut hist = if lo <= hi {
+            Self::new(bins, lo, hi)
+        } else {
+            Self::new(bins, 0.0, 1.0)
+        };
+        hist.add(frame);
+        hist
+    }
+
+    /// Accumulate the pixels of `frame`.
+    pub fn add(&mut self, frame: &Frame) {
+        self.add_values(frame.data());
+    }
+
+    /// Accumulate the pixels of `frame` which are in `mask`.
+    pub fn add_masked(&mut self, frame: &Frame, mask: &Mask) -> Result<()> {
+        self.add_values(&mask.select(frame)?);
+        Ok(())
+    }
+
+    /// Accumulate arbitrary values.
+    pub fn add_values(&mut self, values: &[f32]) {
+        let bins = self.counts.len();
+        let scale = bins as f32 / (self.hi - self.lo);
+        for &v in values {
+            if v.is_nan() {
+                self.nan += 1;
+            } else if v < self.lo {
+                self.below += 1;
+            } else if v > self.hi {
+                self.above += 1;
+            } else {
+                let bin = (((v - self.lo) * scale) as usize).min(bins - 1);
+                self.counts[bin] += 1;
+            }
+        }
+    }
+
+    /// Forget everything accumulated so far, keeping the bins.
+    pub fn reset(&mut self) {
+        self.counts.iter_mut().for_each(|count| *count = 0);
+        self.below = 0;
+        self.above = 0;
+        self.nan = 0;
+    }
+
+    /// Counts in each bin.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Lower and upper edges of bin `i`.
+    pub fn bin_edges(&self, i: usize) -> (f32, f32) {
+        let width = self.bin_width();
+        (self.lo + i as f32 * width, self.lo + (i + 1) as f32 * width)
+    }
+
+    pub fn bin_width(&self) -> f32 {
+        (self.hi - self.lo) / self.counts.len() as f32
+    }
+
+    /// Number of values below and above the histogram range, respectively.
+    pub fn out_of_range(&self) -> (u64, u64) {
+        (self.below, self.above)
+    }
+
+    /// Number of NaN values seen.
+    pub fn nan_count(&self) -> u64 {
+        self.nan
+    }
+
+    /// Number of non-NaN values accumulated, including those out of range.
+    pub fn total(&self) -> u64 {
+        self.below + self.above + self.counts.iter().sum::<u64>()
+    }
+
+    /// Number of values at or above `value`, to the resolution of the bins
+    /// (the bin containing `value` is counted in full, and values below the
+    /// histogram range are never counted).
+    pub fn count_at_least(&self, value: f32) -> u64 {
+        if value > self.hi {
+            return self.above;
+        }
+        let first = ((value - self.lo) / self.bin_width()).max(0.0) as usize;
+        self.above + self.counts.iter().skip(first).sum::<u64>()
+    }
+
+    /// Estimate of the `p`th percentile (0 to 100) of the accumulated values,
+    /// interpolating linearly within the bin it falls in. Values outside the
+    /// range count as being at its ends. None if nothing has been accumulated.
+    pub fn percentile(&self, p: f32) -> Option<f32> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let target = (p.clamp(0.0, 100.0) / 100.0) * total as f32;
+        let mut seen = self.below as f32;
+        if target <= seen {
+            return Some(self.lo);
+        }
+        for (i, &count) in self.counts.iter().enumerate() {
+            if count > 0 && seen + count as f32 >= target {
+                let (lo, hi) = self.bin_edges(i);
+                return Some(lo + (hi - lo) * (target - seen) / count as f32);
+            }
+            seen += count as f32;
+        }
+        Some(self.hi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameHistogram;
+    use crate::frame::{Frame, Mask};
+
+    #[test]
+    fn bins_values(){
+        let mut hist = FrameHistogram::new(4, 0.0, 4.0);
+        hist.add_values(&[-1.0, 0.0, 0.5, 1.5, 3.9, 4.0, 5.0, f32::NAN]);
+        assert_eq!(hist.counts(), &[2, 1, 0, 2]);
+        assert_eq!(hist.out_of_range(), (1, 1));
+        assert_eq!(hist.nan_count(), 1);
+        assert_eq!(hist.total(), 7);
+        assert_eq!(hist.count_at_least(3.0), 3);
+    }
+
+    #[test]
+    fn percentiles_of_uniform_values(){
+        let frame = Frame::new(100, 100, (0..10_000).map(|i| i as f32 / 100.0).collect()).unwrap();
+        let hist = FrameHistogram::of(&frame, 1000);
+        for p in [1.0, 25.0, 50.0, 99.0] {
+            let estimate = hist.percentile(p).unwrap();
+            assert!((estimate - p).abs() < 0.2, "p{p} = {estimate}");
+        }
+        assert_eq!(FrameHistogram::new(10, 0.0, 1.0).percentile(50.0), None);
+    }
+
+    #[test]
+    fn accumulates_across_frames(){
+        let mut hist = FrameHistogram::new(10, 0.0, 10.0);
+        hist.add(&Frame::new(1, 2, vec![1.0, 2.0]).unwrap());
+        hist.add(&Frame::new(1, 2, vec![8.0, 9.0]).unwrap());
+        assert_eq!(hist.total(), 4);
+        hist.reset();
+        assert_eq!(hist.total(), 0);
+        let mask = Mask::new(1, 2, vec![false, true]).unwrap();
+        hist.add_masked(&Frame::new(1, 2, vec![-5.0, 3.0]).unwrap(), &mask).unwrap();
+        assert_eq!((hist.total(), hist.out_of_range()), (1, (0, 0)));
+        assert!(hist.add_masked(&Frame::zeros(2, 1), &mask).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::{Arc, Mutex};
+
+/// Commands to delete images whose handles have been dropped, shared between
+/// a session and its handles. The session sends them before its next command.
+pub(crate) type Graveyard = Arc<Mutex<Vec<String>>>;
+
+/// An image in a milk session which is deleted (with `rmim`) when the handle
+/// is dropped, so temporary images made by Rust code don't pile up in
+/// long-lived sessions. Returned by `Milk::mk2d()` and `Milk::load_fits()`.
+///
+/// The delete command is sent just before the session's next command (or
+/// when the session is closed), not from the handle's `drop` itself.
+///
+/// # Example
+/// ```
+/// use milkrs::Milk;
+/// let mut milk = Milk::new().unwrap();
+/// {
+///     let tmp = milk.mk2d("tmp", 64, 64)?;
+///     milk.cmd(&format!("imcp2shm {} tmps", tmp.name()))?;
+/// }
+/// milk.sync()?;  // "rmim tmp" is sent ahead of the sync
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct ImageHandle {
+    name: String,
+    graveyard: Graveyard,
+    delete_on_drop: bool,
+}
+
+impl ImageHandle {
+    pub(crate) fn new(name: &str, graveyard: &Graveyard) -> Self {
+        Self {
+            name: name.to_string(),
+            graveyard: graveyard.clone(),
+            delete_on_drop: true,
+        }
+    }
+
+    /// Name of the image in milk.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Choose whether the image is deleted when the handle is dropped (the
+    /// default) or left in the session.
+    pub fn set_delete_on_drop(&mut self, delete_on_drop: bool) {
+        self.delete_on_drop = delete_on_drop;
+    }
+
+    /// Give up the handle without deleting the image, returning its name.
+    pub fn keep(mut self) -> String {
+        self.delete_on_drop = false;
+        std::mem::take(&mut self.name)
+    }
+}
+
+impl Drop for ImageHandle {
+    fn drop(&mut self) {
+        if self.delete_on_drop {
+            let mut graveyard = self.graveyard.lock().unwrap_or_else(|e| e.into_inner());
+            graveyard.push(format!("rmim {}", self.name));
+        }
+    }
+}
@@ -1,15 +1,164 @@
-use std::process::{Command, Stdio, Child};
-use std::io::{Write};
-use std::fs::{File};
+use std::process::{Child, ExitStatus};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, IoSlice, Write};
+use std::os::fd::AsRawFd;
+use std::fs::{self, File};
 use std::error;
-use rand::prelude::*;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub mod display;
+pub mod frame;
+pub mod histogram;
+pub mod modes;
+pub mod quality;
+pub mod realtime;
+pub mod sanitize;
+pub mod temporal;
+pub mod testkit;
+mod audit;
+mod builder;
+mod checked;
+mod discover;
+mod events;
+mod image;
+mod log;
+mod pipeline;
+mod query;
+pub mod config;
+mod resources;
+mod retry;
+mod scratch;
+mod sender;
+mod shared;
+mod state;
+mod stream;
+mod telemetry;
+mod transaction;
+#[cfg(feature = "systemd")]
+pub mod systemd;
+pub use audit::{AuditLog, AuditRecord};
+pub use builder::MilkBuilder;
+pub use checked::{CmdOutcome, ModuleUnavailable};
+pub use discover::{discover_sessions, DiscoveredSession};
+pub use events::MilkEvent;
+pub use image::ImageHandle;
+pub use log::{LogLevel, LogLine, LogSource, MilkLogEvent};
+pub use pipeline::Pipeline;
+pub use query::{QueryValue, ResultSpec, RESULT_PLACEHOLDER};
+pub use resources::{ResourceMonitor, ResourceUsage};
+pub use retry::RetryPolicy;
+pub use scratch::{gc, ScratchDir};
+pub use sender::CommandSender;
+pub use shared::SharedMilk;
+pub use state::SessionState;
+pub use stream::{StreamName, MAX_STREAM_NAME_LEN};
+pub use telemetry::TelemetryWriter;
+pub use transaction::Transaction;
 
 type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
+/// Largest write to a fifo which is guaranteed not to be interleaved with
+/// writes from other processes sharing it.
+#[cfg(target_os = "linux")]
+pub const PIPE_BUF: usize = 4096;
+/// Largest write to a fifo which is guaranteed not to be interleaved with
+/// writes from other processes sharing it (the POSIX minimum).
+#[cfg(not(target_os = "linux"))]
+pub const PIPE_BUF: usize = 512;
+
+/// How long `cmd_checked()` waits for milk, unless set with
+/// `MilkBuilder::check_timeout()`.
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// This struct allows interacting with a live Milk session
 pub struct Milk {
-    milk_process: Child,
-    fifo_pipe: File,
+    id: String,
+    label: String,
+    session: Session,
+    config: MilkBuilder,
+    next_id: u64,
+    completed: Option<CommandId>,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    fmt_buf: String,
+    on_disconnect: Option<Box<dyn FnMut(Option<ExitStatus>) + Send>>,
+    disconnected: bool,
+    closed: bool,
+    heartbeat: Heartbeat,
+    queue: Vec<String>,
+    graveyard: image::Graveyard,
+    writer: Option<sender::Writer>,
+    state: SessionState,
+    checkpoints: Vec<(String, SessionState)>,
+    /// whether each command passed to `cmd_from()` was known to milk
+    known_commands: HashMap<String, bool>,
+}
+
+/// State of the heartbeat configured with `MilkBuilder::heartbeat()`.
+#[derive(Default)]
+struct Heartbeat {
+    /// the outstanding heartbeat and when it was sent
+    pending: Option<(CommandId, Instant)>,
+    /// when the last heartbeat was answered
+    answered: Option<Instant>,
+}
+
+/// What sits behind a Milk instance: either a running milk process fed
+/// through a fifo, or (in dry-run mode) a buffer of the commands sent.
+enum Session {
+    Live {
+        milk_process: Child,
+        fifo_pipe: File,
+        fifo_name: String,
+        scratch: ScratchDir,
+        log: Option<Receiver<LogLine>>,
+        tails: Vec<JoinHandle<()>>,
+        /// set once milk prints a line given to `MilkBuilder::alert_on()`
+        alerted: Arc<AtomicBool>,
+    },
+    DryRun {
+        commands: Vec<String>,
+    },
+}
+
+/// Identifier assigned to a command submitted with `Milk::cmd_tracked()`.
+/// Ids are handed out in submission order, so comparing two ids tells you
+/// which command was sent first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommandId(u64);
+
+/// Progress of a tracked command, as reported by `Milk::status()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    /// milk has not yet reached this command
+    Pending,
+    /// milk has finished executing this command
+    Completed,
+}
+
+/// Hook into the commands passing through a Milk session, e.g., for audit
+/// logging, rewriting stream names, or collecting commands instead of sending
+/// them. Closures of the form `FnMut(&str) -> Vec<String>` are interceptors too.
+/// Interceptors must be `Send`, so that sessions can be moved between threads.
+pub trait Interceptor: Send {
+    /// Called with each command before it is written. Returns the commands
+    /// that should be passed on in its place: the command itself to let it
+    /// through unchanged, nothing to drop it, or several to inject extras.
+    fn before(&mut self, command: &str) -> Vec<String>;
+
+    /// Called when milk is seen to have executed every command up to and
+    /// including the tracked command `id`.
+    fn completed(&mut self, _id: CommandId) {}
+}
+
+impl<F: FnMut(&str) -> Vec<String> + Send> Interceptor for F {
+    fn before(&mut self, command: &str) -> Vec<String> {
+        self(command)
+    }
 }
 
 /// This allows the clean exiting of the milk session when the
@@ -17,21 +166,21 @@ pub struct Milk {
 impl Drop for Milk {
     /// `drop(milk)` gracefully exits the Milk session by sending an exit command
     /// to the attached fifo pipe. Then there is a blocking wait before continuing
-    /// execution. This is currently the cleanest way to synchronise the main rust
-    /// process with the milk one - by dropping the Milk instance, e.g.,:
+    /// execution. Dropping the Milk instance synchronises the main rust process
+    /// with the milk one (see `Milk::sync()` to do so without exiting), e.g.,:
     /// ```
     /// use milkrs::Milk;
     /// let mut milk = Milk::new().unwrap();
-    /// milk.cmd("writef2file \"/tmp/out.txt\" 0.5");
+    /// milk.cmd("writef2file \"/tmp/out.txt\" 0.5").unwrap();
     /// // --- at this point we don't know if the above command has finished.
     /// drop(milk);
     /// // --- now we can be sure that the command has been executed.
     /// ``` 
     fn drop(&mut self) {
-        // send exit signal to milk fifo
-        self.cmd("exit");
-        // if successfully exited then this next call will pass without stalling.
-        self.milk_process.wait().expect("couldn't wait?");
+        if !self.closed {
+            let _ = self.reap().and_then(|()| self.flush());
+            self.shut_down().expect("couldn't wait?");
+        }
     }
 }
 
@@ -45,68 +194,348 @@ impl Milk {
     /// let milk = Milk::new().unwrap();
     /// ```
     pub fn new() -> Result<Self> {
-        Ok(Self::new_named(None)?)
+        Self::new_named(None)
     }
 
     /// Same as new(), but provides an optional process name for Milk. new is an
     /// alias for this function with name set to None.
     pub fn new_named(name: Option<&str>) -> Result<Self> {
-        let mut rng = thread_rng();
-        let fifo_name = format!("/tmp/.fifo.{:06}",rng.gen_range(0..=1_000_000));
-        
-        let mkfifo = Command::new("mkfifo")
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .arg(fifo_name.clone())
-            .status()?;
-        
-        match mkfifo.success() {
-            false => return Err("Couldn't create pipe!".into()),
-            _ => {}
+        let mut builder = Self::builder();
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        builder.build()
+    }
+
+    /// Creates a Milk session configured by the profile in the TOML file at
+    /// `path` - see the `config` module docs for the format.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// std::fs::write("/tmp/aol0.toml", "name = \"aol0\"\nstartup_cmds = [\"listim\"]\n").unwrap();
+    /// let milk = Milk::from_config("/tmp/aol0.toml").unwrap();
+    /// ```
+    pub fn from_config(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        MilkBuilder::from_config(path)?.build()
+    }
+
+    /// Run `commands` in a fresh milk session, wait for milk to exit and clean
+    /// up, returning its exit status. For scripts and CI jobs which have no
+    /// use for a session beyond that.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let status = Milk::exec_once(&["mk2Dim im1 64 64", "imcp2shm im1 ims1"])?;
+    /// assert!(status.success());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn exec_once(commands: &[&str]) -> Result<ExitStatus> {
+        Self::builder().exec_once(commands)
+    }
+
+    /// Returns a builder for configuring a Milk session before it is started.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let milk = Milk::builder()
+    ///     .name("mysession")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn builder() -> MilkBuilder {
+        MilkBuilder::new()
+    }
+
+    /// Wrap a session in a Milk instance with no commands sent yet.
+    fn from_session(session: Session, config: MilkBuilder, id: String, label: String) -> Self {
+        Self {
+            id,
+            label,
+            session,
+            config,
+            next_id: 0,
+            completed: None,
+            interceptors: Vec::new(),
+            fmt_buf: String::new(),
+            on_disconnect: None,
+            disconnected: false,
+            closed: false,
+            heartbeat: Heartbeat::default(),
+            queue: Vec::new(),
+            graveyard: image::Graveyard::default(),
+            writer: None,
+            state: SessionState::default(),
+            checkpoints: Vec::new(),
+            known_commands: HashMap::new(),
+        }
+    }
+
+    /// Identifier for this session, unique within the running program, used
+    /// in audit records.
+    pub fn session_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Label identifying this session in audit records, verbose output and
+    /// error messages - see `MilkBuilder::label()`.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Process id of the milk process, or None for dry-run sessions.
+    pub fn pid(&self) -> Option<u32> {
+        match &self.session {
+            Session::Live { milk_process, .. } => Some(milk_process.id()),
+            Session::DryRun { .. } => None,
+        }
+    }
+
+    /// The session's scratch directory, which holds its fifo and any
+    /// temporary files, or None for dry-run sessions.
+    pub fn scratch_dir(&self) -> Option<&ScratchDir> {
+        match &self.session {
+            Session::Live { scratch, .. } => Some(scratch),
+            Session::DryRun { .. } => None,
+        }
+    }
+
+    /// Lines printed by milk, as they arrive, or None unless the session was
+    /// built with `MilkBuilder::capture_output()`. Errors reported by milk
+    /// modules while running in the background show up here. A restart
+    /// replaces the channel.
+    pub fn log_lines(&self) -> Option<&Receiver<LogLine>> {
+        match &self.session {
+            Session::Live { log, .. } => log.as_ref(),
+            Session::DryRun { .. } => None,
+        }
+    }
+
+    /// The milk process itself, e.g., for sending it signals, or None for
+    /// dry-run sessions. Waiting on it or killing it leaves the session
+    /// unusable, but dropping it afterwards is still safe.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// if let Some(process) = milk.process_mut() {
+    ///     println!("milk is running as {}", process.id());
+    /// }
+    /// ```
+    pub fn process_mut(&mut self) -> Option<&mut Child> {
+        match &mut self.session {
+            Session::Live { milk_process, .. } => Some(milk_process),
+            Session::DryRun { .. } => None,
         }
-        
-        let milk_process = Command::new("milk")
-            .arg("-f")
-            .arg("-F")
-            .arg(fifo_name.clone())
-            .args(match name {
-                Some(name) => vec!["-n",name],
-                None => vec![]
-            })
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .stdin(Stdio::null())
-            .spawn()
-            .expect("Failed to spawn milk process");
-        
-        let fifo_pipe = File::options()
-            .create(false)
-            .read(false)
-            .write(true)
-            .append(true)
-            .open(fifo_name.clone())?;
-        
-        let milk = Self {
-            milk_process: milk_process,
-            fifo_pipe: fifo_pipe,
-        };
-        Ok(milk)
     }
 
     /// Pass a command to the Milk session
     ///
+    /// Commands must be a single line: anything containing a newline or other
+    /// control character is rejected, since e.g., `"a\nexit"` would silently
+    /// run two commands. Use `cmd_multi()` to send several lines on purpose.
+    /// Unbalanced double quotes are rejected too (see the `sanitize` module).
+    ///
     /// # Example
     /// ```
     /// use milkrs::Milk;
     /// let mut milk = Milk::new().unwrap();       // create milk instance
-    /// milk.cmd("mk3Dim out1 512 512 512");   // make 512 x 512 x 512 image
-    /// milk.cmd("imcp2shm out1 outs1");       // copy image to shm
+    /// milk.cmd("mk3Dim out1 512 512 512")?;  // make 512 x 512 x 512 image
+    /// milk.cmd("imcp2shm out1 outs1")?;      // copy image to shm
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn cmd(&mut self, command: &str) -> Result<()> {
+        self.reap()?;
+        if self.interceptors.is_empty() && !self.config.buffered {
+            return self.send(command);
+        }
+        let commands = self.intercept(command);
+        self.send_all(&commands)
+    }
+
+    /// Pass a command to the Milk session and wait for milk to run it,
+    /// collecting what it printed along the way to tell whether it worked.
+    /// Needs a session built with `MilkBuilder::capture_output()`; the lines
+    /// collected (and any waiting beforehand) are taken from `log_lines()`.
+    ///
+    /// The end of the command's output is found by following it with a
+    /// made-up command which milk echoes or complains about, so this relies
+    /// on milk flushing its output after each command. Output printed to
+    /// stderr may be attributed to the next checked command if it arrives
+    /// after milk's reply on stdout. Fails with an `io::Error` of kind
+    /// `TimedOut` if milk hasn't finished within the session's check timeout
+    /// (see `MilkBuilder::check_timeout()`).
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::builder().capture_output(true).build().unwrap();
+    /// let outcome = milk.cmd_checked("mk2Dim im1 64 64")?;
+    /// if !outcome.succeeded() {
+    ///     eprintln!("mk2Dim failed: {:?}", outcome.errors());
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn cmd_checked(&mut self, command: &str) -> Result<CmdOutcome> {
+        let mut outcome = CmdOutcome { command: command.to_string(), output: Vec::new() };
+        if let Session::DryRun { .. } = self.session {
+            self.cmd(command)?;
+            return Ok(outcome);
+        }
+        let Some(log) = self.log_lines() else {
+            return Err(format!("milk session {}: cmd_checked() needs capture_output()", self.label).into());
+        };
+        // anything printed before now belongs to earlier commands
+        while log.try_recv().is_ok() {}
+        self.cmd(command)?;
+        self.reap()?;
+        self.flush()?;
+        self.next_id += 1;
+        let marker = format!("milkrs_checked_{}_{}", self.id.replace('-', "_"), self.next_id);
+        self.write_line(&marker)?;
+        let timeout = self.config.check_timeout.unwrap_or(DEFAULT_CHECK_TIMEOUT);
+        let started = Instant::now();
+        let Session::Live { milk_process, log: Some(log), .. } = &mut self.session else {
+            unreachable!("checked above");
+        };
+        loop {
+            match log.recv_timeout(Duration::from_millis(10)) {
+                Ok(line) if line.text.contains(&marker) => break,
+                Ok(line) => outcome.output.push(line),
+                Err(_) => {
+                    if let Some(status) = milk_process.try_wait()? {
+                        return Err(format!(
+                            "milk session {} exited while running {command:?} ({status})", self.label,
+                        ).into());
+                    }
+                    if started.elapsed() >= timeout {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, format!(
+                            "milk session {} didn't finish {command:?} within {timeout:?}", self.label,
+                        )).into());
+                    }
+                }
+            }
+        }
+        outcome.output.extend(log.try_iter().filter(|line| !line.text.contains(&marker)));
+        Ok(outcome)
+    }
+
+    /// Pass a command provided by milk module `module` to the Milk session,
+    /// failing with `ModuleUnavailable` if milk doesn't know the command,
+    /// rather than it silently doing nothing.
+    ///
+    /// The first use of each command is sent with `cmd_checked()` to find
+    /// out; after that the command is sent as with `cmd()` if milk knew it,
+    /// or fails straight away if not. Without `capture_output()` (and in dry
+    /// runs) there is no way to tell, so commands are always sent.
+    pub fn cmd_from(&mut self, module: &str, command: &str) -> Result<()> {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        let capturing = matches!(self.session, Session::Live { log: Some(_), .. });
+        match self.known_commands.get(name) {
+            Some(true) => self.cmd(command),
+            Some(false) => Err(ModuleUnavailable { module: module.to_string(), command: name.to_string() }.into()),
+            None if !capturing => self.cmd(command),
+            None => {
+                let outcome = self.cmd_checked(command)?;
+                let known = !outcome.events().iter().any(|event| {
+                    matches!(event, MilkLogEvent::CommandNotFound { command } if command == name)
+                });
+                self.known_commands.insert(name.to_string(), known);
+                if known {
+                    Ok(())
+                } else {
+                    Err(ModuleUnavailable { module: module.to_string(), command: name.to_string() }.into())
+                }
+            }
+        }
+    }
+
+    /// Format a command straight into a buffer reused between calls and pass
+    /// it to the Milk session, avoiding an allocation per command when sending
+    /// formatted commands at high rates.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// for gain in [0.1, 0.2, 0.3] {
+    ///     milk.cmdf(format_args!("writef2file \"/tmp/gain.txt\" {gain}"))?;
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn cmdf(&mut self, args: fmt::Arguments) -> Result<()> {
+        let mut buf = std::mem::take(&mut self.fmt_buf);
+        buf.clear();
+        let result = match fmt::Write::write_fmt(&mut buf, args) {
+            Ok(()) => self.cmd(&buf),
+            Err(e) => Err(e.into()),
+        };
+        self.fmt_buf = buf;
+        result
+    }
+
+    /// A handle for submitting commands from other threads, which can be
+    /// cloned and sent anywhere - see `CommandSender`. Fails for dry-run and
+    /// closed sessions.
+    pub fn sender(&mut self) -> Result<CommandSender> {
+        if self.closed {
+            return Err(format!("milk session {} has been closed", self.label).into());
+        }
+        if self.writer.is_none() {
+            let Session::Live { fifo_pipe, .. } = &self.session else {
+                return Err("dry-run sessions can't hand out senders".into());
+            };
+            let fifo_pipe = fifo_pipe.try_clone()?;
+            self.writer = Some(sender::Writer::start(
+                fifo_pipe, self.config.clone(), self.id.clone(), self.label.clone(),
+            ));
+        }
+        Ok(self.writer.as_ref().expect("just started").sender())
+    }
+
+    /// Pass multi-line text to the Milk session, one command per line (blank
+    /// lines are skipped). The lines are sent as one batch, as with `cmds()`.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// milk.cmd_multi("mk2Dim im1 64 64\nimcp2shm im1 ims1\n")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
     /// ```
-    pub fn cmd(&mut self, command: &str) {
-        write!(self.fifo_pipe, "{command}\n").expect("couldn't write commmand string");
+    pub fn cmd_multi(&mut self, text: &str) -> Result<()> {
+        self.cmds(text.lines().filter(|line| !line.trim().is_empty()).collect())
     }
 
-    /// Pass a vector of commands to the Milk session
+    /// Register an interceptor which sees (and may rewrite) every command
+    /// passed to `cmd()`. Interceptors run in the order they were added, each
+    /// one receiving the output of the previous.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// // prefix every stream name created by this program
+    /// milk.add_interceptor(|command: &str| vec![command.replace("imcp2shm out1 ", "imcp2shm testA_")]);
+    /// milk.cmd("mk2Dim out1 64 64")?;
+    /// milk.cmd("imcp2shm out1 out1")?;  // sent as "imcp2shm out1 testA_out1"
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Pass a vector of commands to the Milk session. The whole batch is
+    /// written with as few (vectored) writes as possible, which is much
+    /// cheaper than calling `cmd()` for each when sending many commands.
+    ///
+    /// Each write is kept within `PIPE_BUF` bytes, splitting the batch between
+    /// commands, so that other writers to the same fifo can't interleave with
+    /// a command. Batches too big for a single write are an error instead if
+    /// the session was built with `atomic_batches(true)`.
     ///
     /// # Example
     /// ```
@@ -115,21 +544,913 @@ impl Milk {
     /// milk.cmds(vec![
     ///     "mk3Dim out1 512 512 512",  // make 512 x 512 x 512 image
     ///     "imcp2shm out1 outs1",      // copy image to shm
-    /// ]);
+    /// ])?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
     /// ```
-    pub fn cmds(&mut self, commands: Vec<&str>) {
+    pub fn cmds(&mut self, commands: Vec<&str>) -> Result<()> {
+        self.reap()?;
+        let mut lines = Vec::with_capacity(commands.len());
         for command in commands {
-            self.cmd(command);
+            if self.interceptors.is_empty() {
+                lines.push(command.to_string());
+            } else {
+                lines.extend(self.intercept(command));
+            }
+        }
+        self.send_all(&lines)
+    }
+
+    /// Send the commands queued in buffered mode (see
+    /// `MilkBuilder::buffered()`) to milk as one batch.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+        let queue = std::mem::take(&mut self.queue);
+        self.write_batch(&queue)
+    }
+
+    /// Commands queued in buffered mode which haven't been sent to milk yet,
+    /// oldest first. Always empty when not in buffered mode.
+    pub fn pending(&self) -> &[String] {
+        &self.queue
+    }
+
+    /// Drop the commands queued in buffered mode without sending them, e.g.,
+    /// to abort queued work. Returns how many were dropped.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::builder().buffered(true).build().unwrap();
+    /// milk.cmd("mk2Dim im1 64 64")?;
+    /// milk.cmd("imcp2shm im1 ims1")?;
+    /// assert_eq!(milk.pending().len(), 2);
+    /// // operator hit stop: neither command ever reaches milk
+    /// assert_eq!(milk.clear_pending(), 2);
+    /// milk.sync()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn clear_pending(&mut self) -> usize {
+        let count = self.queue.len();
+        if count > 0 {
+            self.queue.clear();
+            self.audit("cancel", &[("count", &count.to_string())], None);
+        }
+        count
+    }
+
+    /// Create a `width` x `height` image called `name`, which is deleted when
+    /// the returned handle is dropped.
+    pub fn mk2d(&mut self, name: &str, width: usize, height: usize) -> Result<ImageHandle> {
+        let name = self.prefixed(sanitize::word(name)?);
+        self.cmd(&format!("mk2Dim {name} {width} {height}"))?;
+        Ok(ImageHandle::new(&name, &self.graveyard))
+    }
+
+    /// Load the FITS file at `path` as an image called `name`, which is
+    /// deleted when the returned handle is dropped.
+    pub fn load_fits(&mut self, path: &str, name: &str) -> Result<ImageHandle> {
+        let name = self.prefixed(sanitize::word(name)?);
+        self.cmd(&format!("loadfits {} {name}", sanitize::quote(path)?))?;
+        Ok(ImageHandle::new(&name, &self.graveyard))
+    }
+
+    /// `name` with the session's name prefix (see
+    /// `MilkBuilder::name_prefix()`), for building commands by hand.
+    pub fn prefixed(&self, name: &str) -> String {
+        format!("{}{name}", self.config.name_prefix)
+    }
+
+    /// Validated stream name for `name` in this session, with the session's
+    /// name prefix applied.
+    pub fn stream_name(&self, name: &str) -> Result<StreamName> {
+        StreamName::new(&self.prefixed(name))
+    }
+
+    /// Send the delete commands of image handles dropped since last time.
+    fn reap(&mut self) -> Result<()> {
+        let dead = std::mem::take(&mut *self.graveyard.lock().unwrap_or_else(|e| e.into_inner()));
+        if dead.is_empty() {
+            return Ok(());
+        }
+        let mut lines = Vec::with_capacity(dead.len());
+        for command in &dead {
+            lines.extend(self.intercept(command));
+        }
+        self.send_all(&lines)
+    }
+
+    /// The images, modules and variables this session has set up, as far as
+    /// can be told from the commands sent to it - see `SessionState`.
+    pub fn snapshot_state(&self) -> SessionState {
+        self.state.clone()
+    }
+
+    /// Send the commands recreating `state` (e.g., a snapshot saved by an
+    /// earlier run) and wait for milk to run them. They have been through
+    /// the interceptors once already, so are sent as they are.
+    pub fn restore_state(&mut self, state: &SessionState) -> Result<()> {
+        self.reap()?;
+        self.send_all(&state.commands())?;
+        self.sync()
+    }
+
+    /// Wait for milk to run every command so far, then record the session's
+    /// state as checkpoint `name` to roll back to later. An earlier
+    /// checkpoint of the same name is replaced.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// milk.cmd("loadfits \"/tmp/dark.fits\" dark")?;
+    /// milk.checkpoint("after_calibration")?;
+    /// milk.cmds(vec!["mk2Dim flat 8 8", "mk2Dim tmp 8 8"])?;
+    /// // flat and tmp are deleted, dark is kept
+    /// milk.rollback_to("after_calibration")?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn checkpoint(&mut self, name: &str) -> Result<()> {
+        self.sync()?;
+        self.checkpoints.retain(|(n, _)| n != name);
+        self.checkpoints.push((name.to_string(), self.state.clone()));
+        self.audit("checkpoint", &[("name", name)], None);
+        Ok(())
+    }
+
+    /// Names of the checkpoints recorded, oldest first.
+    pub fn checkpoints(&self) -> impl Iterator<Item = &str> {
+        self.checkpoints.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Delete every image made since checkpoint `name` (see
+    /// `snapshot_state()` for which are tracked) and wait for milk to do
+    /// so. Later checkpoints are forgotten. Modules and variables are left as
+    /// they are, as are images which existed at the checkpoint but have been
+    /// changed since.
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        let Some(at) = self.checkpoints.iter().position(|(n, _)| n == name) else {
+            return Err(format!("milk session {} has no checkpoint {name:?}", self.label).into());
+        };
+        self.checkpoints.truncate(at + 1);
+        let images = self.state.images_since(&self.checkpoints[at].1);
+        self.reap()?;
+        // the names are as sent, so skip the interceptors
+        let commands: Vec<String> = images.iter().map(|image| format!("rmim {image}")).collect();
+        self.send_all(&commands)?;
+        self.audit("rollback", &[("name", name), ("deleted", &images.len().to_string())], None);
+        self.sync()
+    }
+
+    /// Start a group of commands which can be undone as a whole - see
+    /// `Transaction`.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Pass a command to the Milk session and get back an id which can be
+    /// used to query its progress with `status()`. Each tracked command is
+    /// followed by a sentinel write, so only use this where you care about
+    /// completion - plain `cmd()` has no such overhead.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::{Milk, CommandStatus};
+    /// let mut milk = Milk::new().unwrap();
+    /// let id = milk.cmd_tracked("mk2Dim im1 64 64")?;
+    /// milk.sync()?;
+    /// assert_eq!(milk.status(id), CommandStatus::Completed);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn cmd_tracked(&mut self, command: &str) -> Result<CommandId> {
+        self.cmd(command)?;
+        self.mark()
+    }
+
+    /// Whether milk has closed its end of the fifo (normally because it has
+    /// exited), as opposed to merely being slow to read it. Always false for
+    /// dry-run sessions.
+    pub fn fifo_closed(&self) -> bool {
+        match &self.session {
+            Session::Live { fifo_pipe, .. } => reader_gone(fifo_pipe),
+            Session::DryRun { .. } => false,
+        }
+    }
+
+    /// Whether the session looks able to execute commands: milk hasn't closed
+    /// the fifo and, if a heartbeat is configured (see
+    /// `MilkBuilder::heartbeat()`), it answered the last one in time. This
+    /// also sends the next heartbeat when one is due, so call it regularly,
+    /// e.g., once per iteration of a supervision loop. With
+    /// `MilkBuilder::unhealthy_on_alert()`, a session which has raised an
+    /// alert is unhealthy too.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// use std::time::Duration;
+    /// let mut milk = Milk::builder()
+    ///     .heartbeat(Duration::from_millis(100), Duration::from_secs(1))
+    ///     .build()
+    ///     .unwrap();
+    /// assert!(milk.is_healthy());
+    /// ```
+    pub fn is_healthy(&mut self) -> bool {
+        if self.closed || self.fifo_closed() {
+            return false;
+        }
+        if self.config.unhealthy_on_alert && self.alerted() {
+            return false;
+        }
+        let Some((interval, threshold)) = self.config.heartbeat else {
+            return true;
+        };
+        if let Some((id, sent)) = self.heartbeat.pending {
+            if self.status(id) == CommandStatus::Pending {
+                return sent.elapsed() <= threshold;
+            }
+            self.heartbeat.pending = None;
+            self.heartbeat.answered = Some(Instant::now());
+        }
+        if self.heartbeat.answered.is_none_or(|answered| answered.elapsed() >= interval) {
+            match self.write_mark() {
+                Ok(id) => self.heartbeat.pending = Some((id, Instant::now())),
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether milk has printed a line matching one of the patterns given to
+    /// `MilkBuilder::alert_on()` since it was (re)started.
+    pub fn alerted(&self) -> bool {
+        match &self.session {
+            Session::Live { alerted, .. } => alerted.load(Ordering::Relaxed),
+            Session::DryRun { .. } => false,
+        }
+    }
+
+    /// Register a callback to run when a command can't be sent because milk
+    /// has closed its end of the fifo. It is passed milk's exit status if it
+    /// has exited, and runs once each time the session goes from connected to
+    /// disconnected.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// milk.on_disconnect(|status| eprintln!("lost milk: {status:?}"));
+    /// ```
+    pub fn on_disconnect(&mut self, callback: impl FnMut(Option<ExitStatus>) + Send + 'static) {
+        self.on_disconnect = Some(Box::new(callback));
+    }
+
+    /// Register a hook to be called with each subsequent lifecycle event of
+    /// the session: every command sent, every completed `sync()`, and the
+    /// exit when the session is dropped. See also `MilkBuilder::on_event()`,
+    /// which also sees the spawn.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::{Milk, MilkEvent};
+    /// let mut milk = Milk::new().unwrap();
+    /// milk.on_event(|event: &MilkEvent| {
+    ///     if let MilkEvent::Command { command, ok: false } = event {
+    ///         eprintln!("failed to send {command}");
+    ///     }
+    /// });
+    /// ```
+    pub fn on_event(&mut self, hook: impl FnMut(&MilkEvent) + Send + 'static) {
+        self.config.hooks.add(hook);
+    }
+
+    /// The commands recorded so far by a dry-run session, in the order they
+    /// would have been sent, or None if this session is talking to a real milk
+    /// process.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::builder().dry_run(true).build().unwrap();
+    /// milk.cmd("mk2Dim im1 64 64").unwrap();
+    /// assert_eq!(milk.recorded_commands(), Some(&["mk2Dim im1 64 64".to_string()][..]));
+    /// ```
+    pub fn recorded_commands(&self) -> Option<&[String]> {
+        match &self.session {
+            Session::Live { .. } => None,
+            Session::DryRun { commands } => Some(commands),
+        }
+    }
+
+    /// Report whether milk has finished executing the tracked command `id`.
+    pub fn status(&mut self, id: CommandId) -> CommandStatus {
+        if self.completed < Some(id) {
+            let latest = self.read_sentinel();
+            if latest > self.completed {
+                self.completed = latest;
+                if let Some(latest) = latest {
+                    for interceptor in self.interceptors.iter_mut() {
+                        interceptor.completed(latest);
+                    }
+                }
+            }
+        }
+        if self.completed >= Some(id) {
+            CommandStatus::Completed
+        } else {
+            CommandStatus::Pending
+        }
+    }
+
+    /// Block until milk has executed every command sent so far. Fails if the
+    /// milk process exits before getting there.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// milk.cmd("writef2file \"/tmp/sync.txt\" 0.5")?;
+    /// milk.sync()?;
+    /// // --- the file has now been written, and milk is still running.
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn sync(&mut self) -> Result<()> {
+        let id = self.mark()?;
+        while self.status(id) == CommandStatus::Pending {
+            if let Session::Live { milk_process, .. } = &mut self.session {
+                if let Some(status) = milk_process.try_wait()? {
+                    let error = format!("milk session {} exited before sync ({status})", self.label);
+                    self.audit("sync", &[("id", &id.0.to_string())], Some(&error));
+                    return Err(error.into());
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.audit("sync", &[("id", &id.0.to_string())], None);
+        self.config.hooks.emit(MilkEvent::Synced);
+        Ok(())
+    }
+
+    /// Run `command` and read back the result it writes to a file, as
+    /// described by `spec`. Each `{result}` in the command is replaced by the
+    /// path of a temporary file; the session waits until milk has run the
+    /// command, then parses the file and deletes it.
+    ///
+    /// Dry-run sessions can't answer queries.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::{Milk, ResultSpec};
+    /// let mut milk = Milk::new().unwrap();
+    /// let value = milk.query("writef2file \"{result}\" 0.25", ResultSpec::f64())?;
+    /// assert_eq!(value.as_f64(), Some(0.25));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn query(&mut self, command: &str, spec: ResultSpec) -> Result<QueryValue> {
+        let Session::Live { scratch, .. } = &self.session else {
+            return Err("dry-run sessions can't answer queries".into());
+        };
+        let temp = scratch.mint("result");
+        let path = spec.path(&temp).to_path_buf();
+        // a result left over from an earlier query must not be mistaken for this one
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.cmd(&command.replace(RESULT_PLACEHOLDER, &path.to_string_lossy()))?;
+        self.sync()?;
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!(
+                "milk session {}: {command:?} didn't write its result to {}: {e}", self.label, path.display(),
+            ))?;
+        fs::remove_file(&path)?;
+        spec.parse(&contents)
+    }
+
+    /// Wait up to `timeout` for the milk process to exit, without asking it
+    /// to. Returns its exit status, or None if it is still running (or this
+    /// is a dry-run session).
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// use std::time::Duration;
+    /// let mut milk = Milk::new().unwrap();
+    /// milk.cmd("exit")?;
+    /// let status = milk.wait_with_timeout(Duration::from_secs(5))?;
+    /// assert!(status.is_some_and(|status| status.success()));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn wait_with_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>> {
+        let Session::Live { milk_process, .. } = &mut self.session else {
+            return Ok(None);
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = milk_process.try_wait()? {
+                return Ok(Some(status));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(1));
         }
     }
+
+    /// Exit the Milk session as dropping it would, but return milk's exit
+    /// status (None for dry-run sessions), so that a clean exit can be told
+    /// apart from a crash.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let mut milk = Milk::new().unwrap();
+    /// milk.cmd("mk2Dim im1 64 64")?;
+    /// let status = milk.close()?;
+    /// assert!(status.is_some_and(|status| status.success()));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn close(mut self) -> Result<Option<ExitStatus>> {
+        let flushed = self.reap().and_then(|()| self.flush());
+        self.closed = true;
+        let status = self.shut_down()?;
+        flushed?;
+        Ok(status)
+    }
+
+    /// Stop the milk process without waiting for it to get through the
+    /// commands already sent: ask it to terminate (SIGTERM), and if it is
+    /// still running after `grace`, kill it (SIGKILL). Returns its exit status,
+    /// or None for dry-run sessions. Commands sent afterwards fail until the
+    /// session is `restart()`ed.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// use std::time::Duration;
+    /// let mut milk = Milk::new().unwrap();
+    /// let status = milk.kill(Duration::from_secs(1))?;
+    /// assert!(!status.expect("not a dry run").success());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn kill(&mut self, grace: Duration) -> Result<Option<ExitStatus>> {
+        if self.closed {
+            return self.wait_with_timeout(Duration::ZERO);
+        }
+        if let Some(process) = self.process_mut() {
+            // the child may already have been reaped through process_mut(),
+            // in which case its pid may belong to another process by now
+            if process.try_wait()?.is_none() {
+                // SAFETY: try_wait() just found the child unreaped, so its
+                // pid is still its own (if only as a zombie)
+                unsafe {
+                    libc::kill(process.id() as libc::pid_t, libc::SIGTERM);
+                }
+            }
+            if self.wait_with_timeout(grace)?.is_none() {
+                if let Some(process) = self.process_mut() {
+                    process.kill()?;
+                }
+            }
+        }
+        self.closed = true;
+        Ok(self.shut_down()?)
+    }
+
+    /// Replace the milk process with a fresh one with the same configuration,
+    /// killing the old one first (see `kill()`) if it is still running, and
+    /// run the startup commands again. Session-level state like interceptors
+    /// and hooks carries over, but commands tracked before the restart may be
+    /// reported as completed without having been executed.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// use std::time::Duration;
+    /// let mut milk = Milk::builder().startup_cmds(&["mk2Dim im1 64 64"]).build().unwrap();
+    /// milk.cmd("exit")?;
+    /// milk.restart(Duration::from_secs(1))?;  // im1 is made again
+    /// milk.sync()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn restart(&mut self, grace: Duration) -> Result<()> {
+        self.kill(grace)?;
+        let session = self.config.spawn(&self.id);
+        match &session {
+            Ok(Session::Live { milk_process, fifo_name, .. }) => self.audit(
+                "restart",
+                &[("pid", &milk_process.id().to_string()), ("fifo", fifo_name)],
+                None,
+            ),
+            Ok(Session::DryRun { .. }) => self.audit("restart", &[("dry_run", "true")], None),
+            Err(e) => self.audit("restart", &[], Some(&e.to_string())),
+        }
+        self.session = session?;
+        self.state = SessionState::default();
+        self.checkpoints.clear();
+        self.known_commands.clear();
+        self.completed = None;
+        self.heartbeat = Heartbeat::default();
+        self.disconnected = false;
+        self.closed = false;
+        self.config.hooks.emit(MilkEvent::Spawned { pid: self.pid() });
+        self.start_up()
+    }
+
+    /// Run the configured startup commands and wait for them to finish.
+    fn start_up(&mut self) -> Result<()> {
+        if self.config.startup_cmds.is_empty() {
+            return Ok(());
+        }
+        let startup_cmds = self.config.startup_cmds.clone();
+        self.cmds(startup_cmds.iter().map(String::as_str).collect())?;
+        self.sync()
+    }
+
+    /// Send milk the exit command, wait for it to exit and clean up after it.
+    fn shut_down(&mut self) -> io::Result<Option<ExitStatus>> {
+        let mut status = None;
+        // commands already handed to senders go ahead of the exit
+        if let Some(writer) = self.writer.take() {
+            writer.stop();
+        }
+        if let Session::Live { milk_process, fifo_pipe, tails, .. } = &mut self.session {
+            // send exit signal to milk fifo, bypassing any interceptors. If
+            // this fails then milk is already gone and the wait won't block.
+            // With a write deadline the exit must still get through, however
+            // long milk takes to make room for it.
+            if self.config.write_deadline.is_some() {
+                let _ = set_nonblocking(fifo_pipe, false);
+            }
+            let _ = writeln!(fifo_pipe, "exit");
+            // if successfully exited then this next call will pass without stalling.
+            status = Some(milk_process.wait()?);
+            // let the last of milk's output reach its files, unless something
+            // milk started still holds the pipes open
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while tails.iter().any(|tail| !tail.is_finished()) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        match status {
+            Some(status) if !status.success() => {
+                self.audit("exit", &[], Some(&status.to_string()));
+            }
+            _ => self.audit("exit", &[], None),
+        }
+        self.config.hooks.emit(MilkEvent::Exited { status });
+        Ok(status)
+    }
+
+    /// Queue a sentinel write which milk will only reach after every command
+    /// before it has been executed, flushing any commands queued in buffered
+    /// mode first.
+    ///
+    /// In dry-run mode there is nothing to wait for, so the sentinel is
+    /// neither recorded nor waited on.
+    fn mark(&mut self) -> Result<CommandId> {
+        self.reap()?;
+        self.flush()?;
+        if let Some(writer) = &self.writer {
+            writer.flush();
+        }
+        self.write_mark()
+    }
+
+    /// Write a sentinel straight to the fifo, leaving the buffered mode queue
+    /// alone.
+    fn write_mark(&mut self) -> Result<CommandId> {
+        self.next_id += 1;
+        let id = CommandId(self.next_id);
+        if let Session::Live { scratch, .. } = &self.session {
+            let path = scratch.path().join("sync");
+            let sentinel = format!("writef2file {} {}", sanitize::quote(&path.to_string_lossy())?, id.0);
+            self.write_line(&sentinel)?;
+        }
+        Ok(id)
+    }
+
+    /// Write a single (already intercepted) command, recording it in the
+    /// audit log.
+    fn send(&mut self, command: &str) -> Result<()> {
+        sanitize::validate(command).map_err(|e| format!("milk session {}: {e}", self.label))?;
+        self.echo(command);
+        let result = self.write_line(command).map_err(|e| self.check_disconnect(e));
+        if result.is_ok() {
+            self.state.apply(command);
+        }
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.audit("command", &[("command", command)], error.as_deref());
+        self.config.hooks.emit(MilkEvent::Command { command, ok: result.is_ok() });
+        result
+    }
+
+    /// Send a batch of (already intercepted) commands, or in buffered mode
+    /// queue them.
+    fn send_all(&mut self, commands: &[String]) -> Result<()> {
+        for command in commands {
+            sanitize::validate(command).map_err(|e| format!("milk session {}: {e}", self.label))?;
+        }
+        if self.config.buffered {
+            self.queue.extend_from_slice(commands);
+            return Ok(());
+        }
+        self.write_batch(commands)
+    }
+
+    /// Write a batch of (already intercepted and validated) commands,
+    /// recording them in the audit log.
+    fn write_batch(&mut self, commands: &[String]) -> Result<()> {
+        for command in commands {
+            self.echo(command);
+        }
+        let result = self.write_lines(commands).map_err(|e| self.check_disconnect(e));
+        let error = result.as_ref().err().map(|e| e.to_string());
+        for command in commands {
+            if result.is_ok() {
+                self.state.apply(command);
+            }
+            self.audit("command", &[("command", command)], error.as_deref());
+            self.config.hooks.emit(MilkEvent::Command { command, ok: result.is_ok() });
+        }
+        result
+    }
+
+    /// If a write failed because milk closed the fifo, run the disconnect
+    /// callback and explain the error. Any other error is passed through.
+    fn check_disconnect(&mut self, e: Box<dyn error::Error>) -> Box<dyn error::Error> {
+        let broken_pipe = e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe);
+        let Session::Live { milk_process, .. } = &mut self.session else {
+            return e;
+        };
+        if !broken_pipe {
+            return e;
+        }
+        let status = milk_process.try_wait().ok().flatten();
+        if !self.disconnected {
+            self.disconnected = true;
+            if let Some(callback) = &mut self.on_disconnect {
+                callback(status);
+            }
+        }
+        match status {
+            Some(status) => format!("milk session {}: milk has closed the fifo ({status})", self.label).into(),
+            None => format!("milk session {}: milk has closed the fifo", self.label).into(),
+        }
+    }
+
+    /// Pass `command` through the interceptor chain, returning the commands
+    /// to send in its place.
+    fn intercept(&mut self, command: &str) -> Vec<String> {
+        let mut commands = vec![command.to_string()];
+        for interceptor in self.interceptors.iter_mut() {
+            commands = commands.iter()
+                .flat_map(|command| interceptor.before(command))
+                .collect();
+        }
+        commands
+    }
+
+    /// Print a command about to be sent to stderr, in verbose mode.
+    fn echo(&self, command: &str) {
+        if self.config.verbose {
+            eprintln!("[milk {}] {command}", self.label);
+        }
+    }
+
+    /// Record an event in the audit log, if there is one.
+    fn audit(&self, event: &str, fields: &[(&str, &str)], error: Option<&str>) {
+        if let Some(audit) = &self.config.audit {
+            audit.record(&self.id, &self.label, event, fields, error);
+        }
+    }
+
+    /// Write a single line to the fifo (or dry-run buffer), bypassing the
+    /// interceptors. Writes failing because milk isn't currently reading the
+    /// fifo are retried according to the session's retry policy.
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        match &mut self.session {
+            Session::Live { fifo_pipe, .. } => {
+                let atomic = self.config.atomic_batches || self.config.write_deadline.is_some();
+                if atomic && line.len() + 1 > PIPE_BUF {
+                    return Err(format!(
+                        "milk session {}: command of {} bytes is too long to write atomically (PIPE_BUF is {PIPE_BUF})",
+                        self.label, line.len() + 1,
+                    ).into());
+                }
+                let mut slices = [IoSlice::new(line.as_bytes()), IoSlice::new(b"\n")];
+                write_slices(fifo_pipe, &self.config, &mut slices).map_err(|e| labelled(&self.label, e))?;
+                self.disconnected = false;
+            }
+            Session::DryRun { commands } => commands.push(line.to_string()),
+        }
+        Ok(())
+    }
+
+    /// Write several lines to the fifo (or dry-run buffer), bypassing the
+    /// interceptors. Lines are grouped into chunks of at most `PIPE_BUF`
+    /// bytes, each written atomically with one vectored write, unless a
+    /// single line is longer than that.
+    fn write_lines(&mut self, lines: &[String]) -> Result<()> {
+        match &mut self.session {
+            Session::Live { fifo_pipe, .. } => {
+                let total: usize = lines.iter().map(|line| line.len() + 1).sum();
+                if self.config.atomic_batches && total > PIPE_BUF {
+                    return Err(format!(
+                        "milk session {}: batch of {total} bytes is too long to write atomically (PIPE_BUF is {PIPE_BUF})",
+                        self.label,
+                    ).into());
+                }
+                if let Some(line) = lines.iter().find(|line| line.len() + 1 > PIPE_BUF) {
+                    if self.config.write_deadline.is_some() {
+                        return Err(format!(
+                            "milk session {}: command of {} bytes is too long to write atomically (PIPE_BUF is {PIPE_BUF})",
+                            self.label, line.len() + 1,
+                        ).into());
+                    }
+                }
+                let mut start = 0;
+                while start < lines.len() {
+                    let mut end = start + 1;
+                    let mut len = lines[start].len() + 1;
+                    while end < lines.len() && len + lines[end].len() < PIPE_BUF {
+                        len += lines[end].len() + 1;
+                        end += 1;
+                    }
+                    let mut slices: Vec<IoSlice> = lines[start..end].iter()
+                        .flat_map(|line| [IoSlice::new(line.as_bytes()), IoSlice::new(b"\n")])
+                        .collect();
+                    write_slices(fifo_pipe, &self.config, &mut slices).map_err(|e| labelled(&self.label, e))?;
+                    start = end;
+                }
+                self.disconnected = false;
+            }
+            Session::DryRun { commands } => commands.extend_from_slice(lines),
+        }
+        Ok(())
+    }
+
+    /// Last sentinel value written by milk, if any. A missing or half-written
+    /// file just means milk hasn't got there yet.
+    fn read_sentinel(&self) -> Option<CommandId> {
+        match &self.session {
+            Session::Live { scratch, .. } => {
+                let contents = fs::read_to_string(scratch.path().join("sync")).ok()?;
+                contents.trim().parse().ok().map(CommandId)
+            }
+            Session::DryRun { .. } => Some(CommandId(self.next_id)),
+        }
+    }
+}
+
+/// Whether nothing has the other end of the fifo open for reading. Linux
+/// flags the write end of a pipe with POLLERR once the last reader is gone.
+fn reader_gone(fifo_pipe: &File) -> bool {
+    let mut fd = libc::pollfd {
+        fd: fifo_pipe.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    // SAFETY: fd points to exactly one valid pollfd, and the timeout is zero
+    let ready = unsafe { libc::poll(&mut fd, 1, 0) };
+    ready > 0 && fd.revents & (libc::POLLERR | libc::POLLHUP) != 0
+}
+
+/// Wait up to `timeout` for room to write to the fifo, returning whether
+/// there is any.
+fn wait_writable(fifo_pipe: &File, timeout: Duration) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: fifo_pipe.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    // round up, so that a short wait isn't a busy loop
+    let millis = timeout.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int;
+    // SAFETY: fd points to exactly one valid pollfd
+    match unsafe { libc::poll(&mut fd, 1, millis) } {
+        -1 => Err(io::Error::last_os_error()),
+        ready => Ok(ready > 0),
+    }
+}
+
+/// Put the fifo in or out of non-blocking mode.
+pub(crate) fn set_nonblocking(fifo_pipe: &File, nonblocking: bool) -> io::Result<()> {
+    let fd = fifo_pipe.as_raw_fd();
+    // SAFETY: fcntl is called on an open file descriptor owned by fifo_pipe
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = match nonblocking {
+            true => flags | libc::O_NONBLOCK,
+            false => flags & !libc::O_NONBLOCK,
+        };
+        if libc::fcntl(fd, libc::F_SETFL, flags) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// `e` with the label of the session it came from in front of its message,
+/// keeping its kind for callers to match on.
+fn labelled(label: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("milk session {label}: {e}"))
+}
+
+/// Write to the non-blocking fifo, waiting until `deadline` for milk to make
+/// room.
+fn write_by(fifo_pipe: &mut File, slices: &[IoSlice], deadline: Instant) -> io::Result<usize> {
+    loop {
+        match fifo_pipe.write_vectored(slices) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() || !wait_writable(fifo_pipe, left)? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "milk didn't make room in the fifo before the write deadline",
+                    ));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+/// Write `slices` with vectored writes, falling back to a write per slice if
+/// vectored writes aren't supported. Fails early with `BrokenPipe` if milk
+/// has closed the fifo, and with `WouldBlock` if the session's write
+/// deadline passes.
+pub(crate) fn write_slices(fifo_pipe: &mut File, config: &MilkBuilder, slices: &mut [IoSlice]) -> io::Result<()> {
+    let retry = &config.retry;
+    if let Some(deadline) = config.write_deadline {
+        let deadline = Instant::now() + deadline;
+        let mut remaining = slices;
+        while !remaining.is_empty() {
+            if reader_gone(fifo_pipe) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            match write_by(fifo_pipe, remaining, deadline)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => IoSlice::advance_slices(&mut remaining, written),
+            }
+        }
+        return Ok(());
+    }
+    let mut remaining = slices;
+    while !remaining.is_empty() {
+        let written = retry.run(|| {
+            if reader_gone(fifo_pipe) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            fifo_pipe.write_vectored(remaining)
+        }, is_transient);
+        let written = match written {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => written,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                for slice in remaining.iter() {
+                    retry.run(|| fifo_pipe.write_all(slice), is_transient)?;
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        IoSlice::advance_slices(&mut remaining, written);
+    }
+    Ok(())
+}
+
+/// Whether a failed fifo write is worth retrying: milk may just not be
+/// reading the fifo at the moment, e.g., while it is being restarted.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(),
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::Interrupted)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Milk;
+    use super::{Milk, CommandStatus, AuditLog, MilkEvent, PIPE_BUF, QueryValue, ResultSpec, LogSource, ModuleUnavailable, Pipeline};
     use std::fs;
-    use rand;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
     
+    #[test]
+    fn sessions_are_send(){
+        fn assert_send<T: Send>() {}
+        assert_send::<Milk>();
+        assert_send::<crate::ImageHandle>();
+    }
+
     #[test]
     fn milk_spawns(){
         Milk::new().expect("milk failed to start");
@@ -141,11 +1462,555 @@ mod tests {
         let randint: u32 = rand::random::<u32>() % 1000; 
         milk.cmds(vec![
             &format!("writef2file \"/tmp/tmp.txt\" {randint}"),
-        ]);
+        ]).expect("couldn't send commands");
         // you usually don't need to drop milk, but doing so blocks the process
         // until all milk commands have finished.
         drop(milk);
         let contents = fs::read_to_string("/tmp/tmp.txt").expect("couldn't open");
         assert_eq!(contents, format!("{randint}\n"));
     }
+
+    #[test]
+    fn tracked_commands_complete(){
+        let mut milk = Milk::new().expect("Failed to start milk");
+        let first = milk.cmd_tracked("writef2file \"/tmp/tmp_tracked.txt\" 1").expect("couldn't send");
+        let second = milk.cmd_tracked("writef2file \"/tmp/tmp_tracked.txt\" 2").expect("couldn't send");
+        assert!(first < second);
+        milk.sync().expect("couldn't sync");
+        assert_eq!(milk.status(first), CommandStatus::Completed);
+        assert_eq!(milk.status(second), CommandStatus::Completed);
+        let contents = fs::read_to_string("/tmp/tmp_tracked.txt").expect("couldn't open");
+        assert_eq!(contents, "2\n");
+    }
+
+    #[test]
+    fn interceptors_rewrite_commands(){
+        let mut milk = Milk::new().expect("Failed to start milk");
+        milk.add_interceptor(|command: &str| vec![command.replace("tmp_a", "tmp_b")]);
+        milk.add_interceptor(|command: &str| vec![command.to_string(), command.replace("3", "4")]);
+        milk.cmd("writef2file \"/tmp/tmp_a.txt\" 3").expect("couldn't send");
+        milk.sync().expect("couldn't sync");
+        let contents = fs::read_to_string("/tmp/tmp_b.txt").expect("couldn't open");
+        assert_eq!(contents, "4\n");
+    }
+
+    #[test]
+    fn dry_run_records_commands(){
+        let mut milk = Milk::builder().dry_run(true).build().expect("dry run failed");
+        assert_eq!(milk.pid(), None);
+        milk.cmds(vec!["mk2Dim im1 64 64", "imcp2shm im1 ims1"]).expect("couldn't record");
+        let id = milk.cmd_tracked("rmim im1").expect("couldn't record");
+        milk.sync().expect("couldn't sync");
+        assert_eq!(milk.status(id), CommandStatus::Completed);
+        assert_eq!(
+            milk.recorded_commands().expect("not a dry run"),
+            ["mk2Dim im1 64 64", "imcp2shm im1 ims1", "rmim im1"],
+        );
+    }
+
+    #[test]
+    fn buffered_commands_can_be_cancelled(){
+        let mut milk = Milk::builder().dry_run(true).buffered(true).build().expect("dry run failed");
+        milk.cmds(vec!["mk2Dim im1 64 64", "imcp2shm im1 ims1"]).expect("couldn't queue");
+        assert!(milk.cmd("a\nexit").is_err());
+        assert_eq!(milk.pending(), ["mk2Dim im1 64 64", "imcp2shm im1 ims1"]);
+        assert_eq!(milk.recorded_commands(), Some(&[][..]));
+        assert_eq!(milk.clear_pending(), 2);
+        milk.cmd("rmim im2").expect("couldn't queue");
+        milk.sync().expect("couldn't sync");
+        assert!(milk.pending().is_empty());
+        assert_eq!(milk.recorded_commands().expect("not a dry run"), ["rmim im2"]);
+    }
+
+    #[test]
+    fn rolls_back_to_checkpoints(){
+        let mut milk = Milk::builder().dry_run(true).build().expect("dry run failed");
+        milk.cmds(vec!["mk2Dim dark 8 8", "x=1"]).expect("couldn't record");
+        milk.checkpoint("calibrated").expect("couldn't checkpoint");
+        milk.cmds(vec!["mk2Dim a 8 8", "imcp2shm a as", "mk2Dim dark 4 4"]).expect("couldn't record");
+        milk.checkpoint("copied").expect("couldn't checkpoint");
+        milk.cmd("mk2Dim b 8 8").expect("couldn't record");
+        assert!(milk.rollback_to("nowhere").is_err());
+        milk.rollback_to("calibrated").expect("couldn't roll back");
+        assert_eq!(milk.checkpoints().collect::<Vec<_>>(), ["calibrated"]);
+        assert_eq!(milk.snapshot_state().images().collect::<Vec<_>>(), ["dark"]);
+        assert_eq!(&milk.recorded_commands().expect("not a dry run")[6..], ["rmim b", "rmim as", "rmim a"]);
+    }
+
+    #[test]
+    fn image_handles_delete_on_drop(){
+        let mut milk = Milk::builder().dry_run(true).build().expect("dry run failed");
+        let tmp = milk.mk2d("tmp", 64, 32).expect("couldn't record");
+        let mut kept = milk.load_fits("/tmp/psf.fits", "psf").expect("couldn't record");
+        kept.set_delete_on_drop(false);
+        assert_eq!(milk.mk2d("out", 8, 8).expect("couldn't record").keep(), "out");
+        drop(tmp);
+        drop(kept);
+        milk.cmd("listim").expect("couldn't record");
+        assert_eq!(
+            milk.recorded_commands().expect("not a dry run"),
+            ["mk2Dim tmp 64 32", "loadfits \"/tmp/psf.fits\" psf", "mk2Dim out 8 8", "rmim tmp", "listim"],
+        );
+    }
+
+    /// Write handle onto a buffer the test can read back
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn audit_log_records_events(){
+        let buf = SharedBuf::default();
+        let mut milk = Milk::builder()
+            .dry_run(true)
+            .label("aol0")
+            .audit_log(AuditLog::new(buf.clone()))
+            .build()
+            .expect("dry run failed");
+        let session = milk.session_id().to_string();
+        assert_eq!(milk.label(), "aol0");
+        milk.cmd("mk2Dim \"im1\" 64 64").expect("couldn't record");
+        milk.sync().expect("couldn't sync");
+        drop(milk);
+        let log = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 4);
+        for (line, event) in lines.iter().zip(["spawn", "command", "sync", "exit"]) {
+            assert!(line.starts_with("{\"timestamp\":"));
+            assert!(line.contains(&format!("\"session\":\"{session}\",\"label\":\"aol0\",\"event\":\"{event}\"")));
+            assert!(line.ends_with("\"outcome\":\"ok\"}"));
+        }
+        assert!(lines[1].contains(r#""command":"mk2Dim \"im1\" 64 64""#));
+    }
+
+    #[test]
+    fn hooks_see_lifecycle_events(){
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let mut milk = Milk::builder()
+            .on_event(move |event: &MilkEvent| seen.lock().unwrap().push(format!("{event:?}")))
+            .build()
+            .expect("Failed to start milk");
+        let pid = milk.pid().expect("no pid");
+        milk.cmd("listim").expect("couldn't send");
+        milk.sync().expect("couldn't sync");
+        drop(milk);
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], format!("Spawned {{ pid: Some({pid}) }}"));
+        assert_eq!(events[1], "Command { command: \"listim\", ok: true }");
+        assert_eq!(events[2], "Synced");
+        assert!(events[3].starts_with("Exited { status: Some("));
+    }
+
+    #[test]
+    fn sync_fails_if_milk_exits(){
+        let mut milk = Milk::new().expect("Failed to start milk");
+        milk.cmd("exit").expect("couldn't send");
+        assert!(milk.sync().is_err());
+    }
+
+    #[test]
+    fn queries_read_results_back(){
+        let mut milk = Milk::new().expect("Failed to start milk");
+        let value = milk.query("writef2file \"{result}\" 42", ResultSpec::i64()).expect("couldn't query");
+        assert_eq!(value, QueryValue::I64(42));
+        let path = "/tmp/tmp_query.txt";
+        let spec = ResultSpec::f64().file(path);
+        let value = milk.query(&format!("writef2file \"{path}\" 1.5"), spec).expect("couldn't query");
+        assert_eq!(value.as_f64(), Some(1.5));
+        assert!(!std::path::Path::new(path).exists());
+        assert!(milk.query("mk2Dim im1 64 64", ResultSpec::f64()).is_err());
+        let mut dry = Milk::builder().dry_run(true).build().expect("couldn't build");
+        assert!(dry.query("writef2file \"{result}\" 1", ResultSpec::f64()).is_err());
+    }
+
+    #[test]
+    fn scratch_dir_is_removed_on_drop(){
+        let milk = Milk::new().expect("Failed to start milk");
+        let path = milk.scratch_dir().expect("no scratch dir").path().to_path_buf();
+        assert!(path.join("fifo").exists());
+        drop(milk);
+        assert!(!path.exists());
+        let milk = Milk::builder().dry_run(true).build().expect("couldn't build");
+        assert!(milk.scratch_dir().is_none());
+    }
+
+    #[test]
+    fn captures_output(){
+        let mut milk = Milk::builder().capture_output(true).build().expect("Failed to start milk");
+        milk.cmd("echo hello there").expect("couldn't send");
+        let line = milk.log_lines().expect("no log").recv_timeout(Duration::from_secs(5)).expect("no output");
+        assert_eq!(line.text, "echo hello there");
+        assert_eq!(line.source, LogSource::Stdout);
+        assert!(Milk::new().expect("Failed to start milk").log_lines().is_none());
+    }
+
+    #[test]
+    fn checked_commands_report_errors(){
+        let mut milk = Milk::builder().capture_output(true).build().expect("Failed to start milk");
+        let outcome = milk.cmd_checked("mk2Dim im1 64 64").expect("couldn't check");
+        assert!(outcome.succeeded());
+        assert_eq!(outcome.output.len(), 1);
+        let outcome = milk.cmd_checked("ERROR: no such image").expect("couldn't check");
+        assert!(!outcome.succeeded());
+        assert_eq!(outcome.errors().len(), 1);
+        assert!(Milk::new().expect("Failed to start milk").cmd_checked("mk2Dim im1 64 64").is_err());
+        milk.cmd("exit").expect("couldn't send");
+        assert!(milk.cmd_checked("mk2Dim im1 64 64").is_err());
+    }
+
+    #[test]
+    fn name_prefix_applies_to_helpers(){
+        let mut milk = Milk::builder().dry_run(true).name_prefix("testA_").build().expect("couldn't build");
+        let fits = milk.load_fits("/tmp/psf.fits", "psf").expect("couldn't send");
+        assert_eq!(fits.keep(), "testA_psf");
+        drop(milk.mk2d("im1", 8, 8).expect("couldn't send"));
+        milk.cmd("listim").expect("couldn't send");
+        assert_eq!(
+            milk.recorded_commands().unwrap(),
+            ["loadfits \"/tmp/psf.fits\" testA_psf", "mk2Dim testA_im1 8 8", "rmim testA_im1", "listim"],
+        );
+        assert_eq!(milk.stream_name("wfs").expect("invalid name").as_str(), "testA_wfs");
+        assert!(milk.stream_name("wfs.0").is_err());
+        assert!(Milk::builder().dry_run(true).name_prefix("0_").build().is_err());
+    }
+
+    #[test]
+    fn output_goes_to_files(){
+        let path = "/tmp/tmp_milk_stdout.log";
+        let _ = fs::remove_file(path);
+        let mut milk = Milk::builder().stdout_to(path).build().expect("Failed to start milk");
+        milk.cmd("echo to the file").expect("couldn't send");
+        milk.close().expect("couldn't close");
+        assert_eq!(fs::read_to_string(path).expect("no output file"), "echo to the file\n");
+    }
+
+    #[test]
+    fn reports_missing_modules(){
+        use std::os::unix::fs::PermissionsExt;
+        // a milk built without the module providing mkgauss
+        let script = "/tmp/milkrs_no_imagegen_milk.sh";
+        fs::write(script, "#!/bin/sh\nmilk \"$@\" | sed -u 's/^mkgauss .*/Command \"mkgauss\" not found/'\n")
+            .expect("couldn't write script");
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755)).expect("couldn't chmod script");
+        let mut milk = Milk::builder().binary(script).capture_output(true).build().expect("Failed to start milk");
+        let e = milk.cmd_from("image_gen", "mkgauss psf 8 8 2").unwrap_err();
+        let missing = e.downcast_ref::<ModuleUnavailable>().expect("not a ModuleUnavailable");
+        assert_eq!((missing.module.as_str(), missing.command.as_str()), ("image_gen", "mkgauss"));
+        // known from now on, without asking milk again
+        assert!(milk.cmd_from("image_gen", "mkgauss psf 4 4 1").is_err());
+        milk.cmd_from("image_filter", "fconv a b c").expect("fconv should be known");
+        milk.cmd_from("image_filter", "fconv a b d").expect("fconv should be known");
+        let e = Pipeline::new().gen_gauss("psf", 8, 8, 2.0).run(&mut milk).unwrap_err();
+        assert!(e.downcast_ref::<ModuleUnavailable>().is_some(), "{e}");
+        milk.close().expect("couldn't close");
+    }
+
+    #[test]
+    fn checked_commands_time_out(){
+        use std::os::unix::fs::PermissionsExt;
+        // a milk which says nothing about commands it doesn't know
+        let script = "/tmp/milkrs_quiet_milk.sh";
+        fs::write(script, "#!/bin/sh\nmilk \"$@\" | sed -u '/milkrs_checked_/d'\n").expect("couldn't write script");
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755)).expect("couldn't chmod script");
+        let mut milk = Milk::builder()
+            .binary(script)
+            .capture_output(true)
+            .check_timeout(Duration::from_millis(200))
+            .build()
+            .expect("Failed to start milk");
+        let start = Instant::now();
+        let e = milk.cmd_checked("mk2Dim im1 8 8").unwrap_err();
+        let e = e.downcast_ref::<io::Error>().expect("not an io::Error");
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2), "took {:?}", start.elapsed());
+        assert!(milk.cmd_from("image_gen", "mkgauss psf 8 8 2").is_err());
+        milk.close().expect("couldn't close");
+    }
+
+    #[test]
+    fn stderr_raises_alerts(){
+        use std::os::unix::fs::PermissionsExt;
+        // a milk which prints to stderr rather than stdout
+        let script = "/tmp/milkrs_stderr_milk.sh";
+        fs::write(script, "#!/bin/sh\nexec milk \"$@\" 1>&2\n").expect("couldn't write script");
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755)).expect("couldn't chmod script");
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let seen = alerts.clone();
+        let mut milk = Milk::builder()
+            .binary(script)
+            .alert_on("segfault")
+            .unhealthy_on_alert(true)
+            .on_event(move |event: &MilkEvent| {
+                if let MilkEvent::Alert { pattern, line } = event {
+                    seen.lock().unwrap().push((pattern.to_string(), line.to_string()));
+                }
+            })
+            .build()
+            .expect("Failed to start milk");
+        milk.cmd("all fine").expect("couldn't send");
+        milk.sync().expect("couldn't sync");
+        assert!(milk.is_healthy());
+        milk.cmd("module segfault in aol0").expect("couldn't send");
+        let mut polls = 0;
+        while !milk.alerted() {
+            polls += 1;
+            assert!(polls < 1000, "no alert raised");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!milk.is_healthy());
+        assert_eq!(*alerts.lock().unwrap(), [("segfault".to_string(), "module segfault in aol0".to_string())]);
+    }
+
+    #[test]
+    fn write_deadline_bounds_blocking(){
+        let deadline = Duration::from_millis(20);
+        let mut milk = Milk::builder().write_deadline(deadline).build().expect("Failed to start milk");
+        milk.cmd("mk2Dim im1 64 64").expect("couldn't send");
+        assert!(milk.cmd(&"x".repeat(PIPE_BUF)).is_err());
+        // a stopped milk never drains the fifo, so it soon fills up
+        let pid = milk.pid().expect("no pid") as libc::pid_t;
+        unsafe {
+            libc::kill(pid, libc::SIGSTOP);
+        }
+        let command = "x".repeat(1000);
+        let error = (0..1000)
+            .find_map(|_| {
+                let started = std::time::Instant::now();
+                let result = milk.cmd(&command);
+                assert!(started.elapsed() < deadline * 10, "blocked for {:?}", started.elapsed());
+                result.err()
+            })
+            .expect("fifo never filled up");
+        let error = error.downcast_ref::<io::Error>().expect("not an io error");
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(error.to_string().starts_with(&format!("milk session {}: ", milk.label())), "{error}");
+        milk.kill(Duration::from_millis(50)).expect("couldn't kill");
+    }
+
+    #[test]
+    fn senders_submit_from_other_threads(){
+        let path = "/tmp/tmp_sender.txt";
+        let mut milk = Milk::new().expect("Failed to start milk");
+        let sender = milk.sender().expect("couldn't get sender");
+        let workers: Vec<_> = (0..4).map(|i| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for j in 0..25 {
+                    sender.send(&format!("writef2file \"{path}.{i}\" {j}")).expect("couldn't send");
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().expect("worker panicked");
+        }
+        milk.sync().expect("couldn't sync");
+        for i in 0..4 {
+            assert_eq!(fs::read_to_string(format!("{path}.{i}")).expect("not written"), "24\n");
+        }
+        assert!(sender.send("bad\ncommand").is_err());
+        sender.send(&format!("writef2file \"{path}\" last")).expect("couldn't send");
+        milk.close().expect("couldn't close");
+        assert_eq!(fs::read_to_string(path).expect("not written before exit"), "last\n");
+        assert!(sender.send("mk2Dim im1 64 64").is_err());
+        assert!(Milk::builder().dry_run(true).build().expect("couldn't build").sender().is_err());
+    }
+
+    #[test]
+    fn labels_default_to_name_then_id(){
+        let milk = Milk::builder().dry_run(true).name("aol1").build().expect("couldn't build");
+        assert_eq!(milk.label(), "aol1");
+        let milk = Milk::builder().dry_run(true).build().expect("couldn't build");
+        assert_eq!(milk.label(), milk.session_id());
+        let error = Milk::builder().label("dm").binary("/nonexistent/milk").build().err().expect("built");
+        assert!(error.to_string().starts_with("milk session dm: "), "{error}");
+        let mut milk = Milk::builder().dry_run(true).label("dm").build().expect("couldn't build");
+        let error = milk.cmd("bad\ncommand").unwrap_err();
+        assert!(error.to_string().starts_with("milk session dm: "), "{error}");
+        let error = milk.cmds(vec!["listim", "bad\ncommand"]).unwrap_err();
+        assert!(error.to_string().starts_with("milk session dm: "), "{error}");
+    }
+
+    #[test]
+    fn exec_once_runs_commands(){
+        let path = "/tmp/tmp_exec_once.txt";
+        let command = format!("writef2file \"{path}\" 7");
+        let status = Milk::exec_once(&[&command]).expect("couldn't run");
+        assert!(status.success());
+        assert_eq!(fs::read_to_string(path).expect("command didn't run"), "7\n");
+        assert!(Milk::exec_once(&["bad\ncommand"]).is_err());
+    }
+
+    #[test]
+    fn reports_exit_status(){
+        use std::os::unix::process::ExitStatusExt;
+        let mut milk = Milk::new().expect("Failed to start milk");
+        assert_eq!(milk.wait_with_timeout(Duration::from_millis(10)).expect("couldn't wait"), None);
+        let status = milk.close().expect("couldn't close");
+        assert!(status.expect("no status").success());
+
+        let mut milk = Milk::new().expect("Failed to start milk");
+        milk.process_mut().expect("no process").kill().expect("couldn't kill");
+        let status = milk.wait_with_timeout(Duration::from_secs(5)).expect("couldn't wait");
+        assert_eq!(status.expect("still running").signal(), Some(libc::SIGKILL));
+        assert!(!milk.close().expect("couldn't close").expect("no status").success());
+    }
+
+    #[test]
+    fn heartbeat_detects_wedged_milk(){
+        let mut milk = Milk::builder()
+            .heartbeat(Duration::from_millis(10), Duration::from_millis(200))
+            .build()
+            .expect("Failed to start milk");
+        for _ in 0..20 {
+            assert!(milk.is_healthy());
+            thread::sleep(Duration::from_millis(5));
+        }
+        // a stopped process keeps the fifo open but never reads it
+        let pid = milk.pid().expect("no pid") as libc::pid_t;
+        unsafe {
+            libc::kill(pid, libc::SIGSTOP);
+        }
+        let mut polls = 0;
+        while milk.is_healthy() {
+            polls += 1;
+            assert!(polls < 1000, "wedged milk still reported healthy");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!milk.fifo_closed());
+        milk.kill(Duration::from_millis(50)).expect("couldn't kill");
+    }
+
+    #[test]
+    fn kill_leaves_reaped_milk_alone(){
+        let mut milk = Milk::new().expect("Failed to start milk");
+        let process = milk.process_mut().expect("not a dry run");
+        process.kill().expect("couldn't kill");
+        let reaped = process.wait().expect("couldn't wait");
+        let status = milk.kill(Duration::from_secs(1)).expect("couldn't kill");
+        assert_eq!(status, Some(reaped));
+    }
+
+    #[test]
+    fn restart_replays_startup_commands(){
+        let path = "/tmp/tmp_restart.txt";
+        let mut milk = Milk::builder()
+            .startup_cmds(&[&format!("writef2file \"{path}\" 1")])
+            .build()
+            .expect("Failed to start milk");
+        let pid = milk.pid();
+        let status = milk.kill(Duration::from_secs(1)).expect("couldn't kill");
+        assert!(!status.expect("no status").success());
+        assert!(milk.cmd("listim").is_err());
+        fs::remove_file(path).expect("startup commands didn't run");
+        milk.restart(Duration::from_secs(1)).expect("couldn't restart");
+        assert_ne!(milk.pid(), pid);
+        assert_eq!(fs::read_to_string(path).expect("startup commands didn't rerun"), "1\n");
+        milk.cmd("listim").expect("couldn't send after restart");
+        milk.sync().expect("couldn't sync");
+    }
+
+    #[test]
+    fn batches_are_written_in_order(){
+        let mut milk = Milk::new().expect("Failed to start milk");
+        let commands: Vec<String> = (0..2000)
+            .map(|i| format!("writef2file \"/tmp/tmp_batch.txt\" {i}"))
+            .collect();
+        milk.cmds(commands.iter().map(String::as_str).collect()).expect("couldn't send batch");
+        milk.sync().expect("couldn't sync");
+        let contents = fs::read_to_string("/tmp/tmp_batch.txt").expect("couldn't open");
+        assert_eq!(contents, "1999\n");
+    }
+
+    #[test]
+    fn atomic_batches_reject_long_batches(){
+        let mut milk = Milk::builder().atomic_batches(true).build().expect("Failed to start milk");
+        let long = format!("writef2file \"/tmp/tmp_atomic.txt\" {}", "9".repeat(PIPE_BUF));
+        assert!(milk.cmd(&long).is_err());
+        assert!(milk.cmds(vec!["writef2file \"/tmp/tmp_atomic.txt\" 1"; 200]).is_err());
+        milk.cmds(vec!["writef2file \"/tmp/tmp_atomic.txt\" 1"; 100]).expect("couldn't send batch");
+    }
+
+    #[test]
+    fn multi_line_commands_are_rejected(){
+        let mut milk = Milk::builder().dry_run(true).build().expect("dry run failed");
+        assert!(milk.cmd("mk2Dim im1 64 64\nexit").is_err());
+        assert!(milk.cmd("mk2Dim im1 64 64\r").is_err());
+        assert!(milk.cmds(vec!["mk2Dim im1 64 64", "rmim\u{1b} im1"]).is_err());
+        milk.cmd("mk2Dim\tim1 64 64").expect("tabs are fine");
+        milk.cmd_multi("mk2Dim im2 64 64\n\nrmim im2\n").expect("couldn't send lines");
+        assert_eq!(
+            milk.recorded_commands().expect("not a dry run"),
+            ["mk2Dim\tim1 64 64", "mk2Dim im2 64 64", "rmim im2"],
+        );
+    }
+
+    #[test]
+    fn formatted_commands(){
+        let mut milk = Milk::builder().dry_run(true).build().expect("dry run failed");
+        for i in 0..3 {
+            milk.cmdf(format_args!("mk2Dim im{i} {} 64", 32 * (i + 1))).expect("couldn't record");
+        }
+        assert!(milk.cmdf(format_args!("rmim {}", "im0\nexit")).is_err());
+        assert_eq!(
+            milk.recorded_commands().expect("not a dry run"),
+            ["mk2Dim im0 32 64", "mk2Dim im1 64 64", "mk2Dim im2 96 64"],
+        );
+    }
+
+    #[test]
+    fn detects_milk_closing_the_fifo(){
+        let mut milk = Milk::new().expect("Failed to start milk");
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        milk.on_disconnect(move |status| {
+            assert!(status.is_some_and(|status| status.success()));
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert!(!milk.fifo_closed());
+        milk.cmd("exit").expect("couldn't send");
+        while !milk.fifo_closed() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // let milk finish exiting so its status is available
+        thread::sleep(Duration::from_millis(50));
+        let e = milk.cmd("listim").unwrap_err();
+        assert!(e.to_string().starts_with(&format!("milk session {}: milk has closed the fifo", milk.label())), "{e}");
+        assert!(milk.cmds(vec!["listim", "listim"]).is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn startup_commands_run_before_build_returns(){
+        let _ = fs::remove_file("/tmp/tmp_startup.txt");
+        let milk = Milk::builder()
+            .startup_cmds(&["writef2file \"/tmp/tmp_startup.txt\" 11"])
+            .build()
+            .expect("Failed to start milk");
+        let contents = fs::read_to_string("/tmp/tmp_startup.txt").expect("couldn't open");
+        assert_eq!(contents, "11\n");
+        drop(milk);
+        assert!(Milk::builder().startup_cmds(&["bad\ncommand"]).build().is_err());
+    }
+
+    #[test]
+    fn spawns_configured_process(){
+        let mut milk = Milk::builder()
+            .binary("milk")
+            .env("MILK_SHM_DIR", "/tmp")
+            .cpus(&[0])
+            .build()
+            .expect("Failed to start milk");
+        milk.sync().expect("couldn't sync");
+        assert!(Milk::builder().binary("/nonexistent/milk").build().is_err());
+    }
 }
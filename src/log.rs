@@ -0,0 +1,312 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+use crate::events::Hooks;
+use crate::MilkEvent;
+
+/// Which of milk's output streams a `LogLine` came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogSource {
+    Stdout,
+    Stderr,
+}
+
+/// A line printed by milk, captured with `MilkBuilder::capture_output()` and
+/// read from `Milk::log_lines()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    /// when the line was read
+    pub time: SystemTime,
+    /// which stream it was printed to
+    pub source: LogSource,
+    /// the line, without its newline
+    pub text: String,
+}
+
+/// How serious a line printed by milk is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+/// A line printed by milk, classified by `MilkLogEvent::parse()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MilkLogEvent {
+    /// milk was given a command it doesn't know, e.g., a typo or a command
+    /// from a module which isn't loaded
+    CommandNotFound {
+        command: String,
+    },
+    /// a module couldn't be loaded, e.g., by `mload`
+    ModuleLoadFailed {
+        module: String,
+        reason: Option<String>,
+    },
+    /// any other line
+    Message {
+        level: LogLevel,
+        text: String,
+    },
+}
+
+impl MilkLogEvent {
+    /// Classify a line printed by milk. Lines are matched on the keywords
+    /// milk's messages use (`error`, `warning`, `not found`, `dlopen`...),
+    /// so unusual wording from third-party modules may only be recognised as
+    /// a plain `Message`.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::{LogLevel, MilkLogEvent};
+    /// let event = MilkLogEvent::parse("ERROR: command \"mk2dim\" not found");
+    /// assert_eq!(event, MilkLogEvent::CommandNotFound { command: "mk2dim".to_string() });
+    /// assert_eq!(event.level(), LogLevel::Error);
+    /// ```
+    pub fn parse(line: &str) -> Self {
+        let text = line.trim();
+        let lower = text.to_ascii_lowercase();
+        if lower.contains("not found") && lower.contains("command") {
+            if let Some(command) = command_not_found(text, &lower) {
+                return Self::CommandNotFound { command };
+            }
+        }
+        if (lower.contains("module") || lower.contains("dlopen") || lower.contains(".so"))
+            && ["cannot load", "can't load", "could not load", "couldn't load", "failed to load", "dlopen"]
+                .iter().any(|failure| lower.contains(failure))
+        {
+            if let Some(module) = shared_object(text).or_else(|| first_quoted(text)) {
+                let reason = text.rsplit_once(": ")
+                    .map(|(_, reason)| reason.trim().to_string())
+                    .filter(|reason| !reason.is_empty() && !reason.contains(&module));
+                return Self::ModuleLoadFailed { module, reason };
+            }
+        }
+        let level = if ["error", "fatal", "failed"].iter().any(|word| lower.contains(word)) {
+            LogLevel::Error
+        } else if lower.contains("warn") {
+            LogLevel::Warning
+        } else {
+            LogLevel::Info
+        };
+        Self::Message { level, text: text.to_string() }
+    }
+
+    /// How serious the event is.
+    pub fn level(&self) -> LogLevel {
+        match self {
+            Self::CommandNotFound { .. } | Self::ModuleLoadFailed { .. } => LogLevel::Error,
+            Self::Message { level, .. } => *level,
+        }
+    }
+}
+
+impl LogLine {
+    /// Classify the line - see `MilkLogEvent::parse()`.
+    pub fn event(&self) -> MilkLogEvent {
+        MilkLogEvent::parse(&self.text)
+    }
+}
+
+/// The command in a "command not found" message, either quoted (`command
+/// "foo" not found`) or in shell style (`foo: command not found`).
+fn command_not_found(text: &str, lower: &str) -> Option<String> {
+    if let Some(command) = first_quoted(text) {
+        return Some(command);
+    }
+    let end = lower.find(": command not found")?;
+    let command = text[..end].rsplit(char::is_whitespace).next()?;
+    (!command.is_empty()).then(|| command.to_string())
+}
+
+/// The first word of `text` naming a shared object, without its directory.
+fn shared_object(text: &str) -> Option<String> {
+    text.split(|c: char| c.is_whitespace() || "\"'`:,()".contains(c))
+        .find(|word| word.ends_with(".so") || word.contains(".so."))
+        .map(|word| word.rsplit('/').next().unwrap_or(word).to_string())
+}
+
+/// The contents of the first pair of double quotes in `text`.
+fn first_quoted(text: &str) -> Option<String> {
+    let (_, rest) = text.split_once('"')?;
+    let (quoted, _) = rest.split_once('"')?;
+    (!quoted.is_empty()).then(|| quoted.to_string())
+}
+
+/// A file which milk's output is appended to, renamed to `<path>.1` (and any
+/// older ones shuffled along) before a line would take it past `max_bytes`.
+#[derive(Debug)]
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    keep: usize,
+    /// line starting every file, e.g., a CSV header
+    header: Option<Vec<u8>>,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, len, max_bytes, keep, header: None })
+    }
+
+    /// Start the file, if empty, and every one rotated to with `header`.
+    pub(crate) fn with_header(mut self, header: &[u8]) -> io::Result<Self> {
+        self.header = Some(header.to_vec());
+        if self.len == 0 {
+            self.write_header()?;
+        }
+        Ok(self)
+    }
+
+    /// Append `line` and a newline, rotating first if it wouldn't fit.
+    pub(crate) fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let size = line.len() as u64 + 1;
+        let header = self.header.as_ref().map_or(0, |header| header.len() as u64 + 1);
+        if self.len > header && self.len + size > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.file.write_all(b"\n")?;
+        self.len += size;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        self.file = File::options().create(true).append(true).open(&self.path)?;
+        self.len = 0;
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if let Some(header) = &self.header {
+            self.file.write_all(header)?;
+            self.file.write_all(b"\n")?;
+            self.len += header.len() as u64 + 1;
+        }
+        Ok(())
+    }
+}
+
+/// Patterns to look for in milk's stderr, and what to do on finding one.
+pub(crate) struct Alerts {
+    pub(crate) patterns: Vec<String>,
+    pub(crate) hooks: Hooks,
+    /// set once any pattern has been seen
+    pub(crate) raised: Arc<AtomicBool>,
+}
+
+impl Alerts {
+    fn check(&self, line: &str) {
+        for pattern in self.patterns.iter().filter(|pattern| line.contains(pattern.as_str())) {
+            self.raised.store(true, Ordering::Relaxed);
+            self.hooks.emit(MilkEvent::Alert { pattern, line });
+        }
+    }
+}
+
+/// Forward each line read from `output` to `lines` and/or `file` on a new
+/// thread, checking it against `alerts`, until milk closes its end.
+pub(crate) fn tail(
+    output: impl Read + Send + 'static,
+    source: LogSource,
+    lines: Option<Sender<LogLine>>,
+    mut file: Option<RotatingFile>,
+    alerts: Option<Alerts>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for text in BufReader::new(output).split(b'\n') {
+            let Ok(text) = text else { break };
+            if let Some(alerts) = &alerts {
+                alerts.check(&String::from_utf8_lossy(&text));
+            }
+            if let Some(rotating) = &mut file {
+                if let Err(e) = rotating.write_line(&text) {
+                    eprintln!("milkrs: stopped writing milk's output to {}: {e}", rotating.path.display());
+                    file = None;
+                }
+            }
+            let Some(lines) = &lines else { continue };
+            let line = LogLine {
+                time: SystemTime::now(),
+                source,
+                text: String::from_utf8_lossy(&text).trim_end_matches('\r').to_string(),
+            };
+            // keep draining even once nobody is listening, so that milk
+            // never blocks on a full pipe
+            let _ = lines.send(line);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogLevel, MilkLogEvent, RotatingFile};
+    use std::fs;
+
+    #[test]
+    fn classifies_lines(){
+        let not_found = |command: &str| MilkLogEvent::CommandNotFound { command: command.to_string() };
+        assert_eq!(MilkLogEvent::parse("Command \"imcp2shmm\" not found"), not_found("imcp2shmm"));
+        assert_eq!(MilkLogEvent::parse("  imcp2shmm: command not found"), not_found("imcp2shmm"));
+        assert_eq!(
+            MilkLogEvent::parse("ERROR: cannot load module /usr/local/milk/lib/libmilkcacao.so: undefined symbol foo"),
+            MilkLogEvent::ModuleLoadFailed {
+                module: "libmilkcacao.so".to_string(),
+                reason: Some("undefined symbol foo".to_string()),
+            },
+        );
+        assert_eq!(
+            MilkLogEvent::parse("Failed to load module \"cacao\""),
+            MilkLogEvent::ModuleLoadFailed { module: "cacao".to_string(), reason: None },
+        );
+        assert_eq!(MilkLogEvent::parse("ERROR: image aol0_wfsim does not exist").level(), LogLevel::Error);
+        assert_eq!(MilkLogEvent::parse("WARNING: stream has no semaphore").level(), LogLevel::Warning);
+        assert_eq!(
+            MilkLogEvent::parse("loading module cacao\n"),
+            MilkLogEvent::Message { level: LogLevel::Info, text: "loading module cacao".to_string() },
+        );
+    }
+
+    #[test]
+    fn rotates_by_size(){
+        let dir = "/tmp/milkrs_rotation_test";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = format!("{dir}/milk.log");
+        let mut file = RotatingFile::open(path.as_ref(), 10, 2).unwrap();
+        for line in ["aaaa", "bbbb", "cccc", "dddd", "eeee", "ffff", "gggg"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "gggg\n");
+        assert_eq!(fs::read_to_string(format!("{path}.1")).unwrap(), "eeee\nffff\n");
+        assert_eq!(fs::read_to_string(format!("{path}.2")).unwrap(), "cccc\ndddd\n");
+        assert!(fs::metadata(format!("{path}.3")).is_err());
+    }
+}
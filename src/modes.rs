@@ -0,0 +1,237 @@
+//! Zernike and Fourier modal bases on square frames, and projection of
+//! frames onto them.
+use crate::frame::{Frame, Mask};
+use crate::Result;
+
+/// Radial order `n` and azimuthal frequency `m` of the Zernike polynomial
+/// with Noll index `j` (starting at 1 for piston). Negative `m` is a sine
+/// term, positive a cosine term.
+///
+/// # Example
+/// ```
+/// use milkrs::modes::noll_to_nm;
+/// assert_eq!(noll_to_nm(1), (0, 0));  // piston
+/// assert_eq!(noll_to_nm(2), (1, 1));  // tip
+/// assert_eq!(noll_to_nm(4), (2, 0));  // defocus
+/// ```
+///
+/// # Panics
+/// If `j` is 0.
+pub fn noll_to_nm(j: usize) -> (usize, i32) {
+    assert!(j > 0, "Noll indices start at 1");
+    let mut n = 0;
+    while j > (n + 1) * (n + 2) / 2 {
+        n += 1;
+    }
+    let p = j - n * (n + 1) / 2 - 1;
+    let m = if n.is_multiple_of(2) { 2 * p.div_ceil(2) } else { 2 * (p / 2) + 1 } as i32;
+    match m {
+        0 => (n, 0),
+        _ if j.is_multiple_of(2) => (n, m),
+        _ => (n, -m),
+    }
+}
+
+/// Zernike polynomial with Noll index `j` on a `size` x `size` frame, over
+/// the pupil inscribed in it and zero outside. Normalised so its variance
+/// over the pupil is 1 (except piston, whose mean is 1).
+///
+/// # Panics
+/// If `j` is 0.
+pub fn zernike(j: usize, size: usize) -> Frame {
+    let (n, m) = noll_to_nm(j);
+    let norm = if m == 0 { ((n + 1) as f64).sqrt() } else { (2.0 * (n + 1) as f64).sqrt() };
+    let mut frame = Frame::zeros(size, size);
+    for (i, value) in frame.data_mut().iter_mut().enumerate() {
+        let (r, theta) = polar(i % size, i / size, size);
+        if r <= 1.0 {
+            let angular = match m {
+                0 => 1.0,
+                m if m > 0 => (m as f64 * theta).cos(),
+                m => (-m as f64 * theta).sin(),
+            };
+            *value = (norm * radial(n, m.unsigned_abs() as usize, r) * angular) as f32;
+        }
+    }
+    frame
+}
+
+/// The first `count` Zernike polynomials in Noll order, starting at piston.
+///
+/// # Example
+/// ```
+/// use milkrs::modes;
+/// let basis = modes::zernike_basis(10, 64);
+/// let frame = modes::combine(&[0.0, 0.5, 0.0, 2.0], &basis[..4]).unwrap();
+/// let coefficients = modes::project(&frame, &basis).unwrap();
+/// assert!((coefficients[3] - 2.0).abs() < 0.05);
+/// ```
+pub fn zernike_basis(count: usize, size: usize) -> Vec<Frame> {
+    (1..=count).map(|j| zernike(j, size)).collect()
+}
+
+/// Fourier mode with `kx` and `ky` cycles across a `size` x `size` frame:
+/// a cosine, or a sine if `sine` is set, with amplitude 1.
+pub fn fourier(kx: i32, ky: i32, sine: bool, size: usize) -> Frame {
+    let mut frame = Frame::zeros(size, size);
+    for (i, value) in frame.data_mut().iter_mut().enumerate() {
+        let (x, y) = ((i % size) as f64, (i / size) as f64);
+        let phase = 2.0 * std::f64::consts::PI * (kx as f64 * x + ky as f64 * y) / size as f64;
+        *value = if sine { phase.sin() } else { phase.cos() } as f32;
+    }
+    frame
+}
+
+/// Cosine and sine Fourier modes for every spatial frequency of at most
+/// `max_cycles` cycles across the frame (in radius), excluding piston and
+/// listed from low to high frequency.
+pub fn fourier_basis(max_cycles: usize, size: usize) -> Vec<Frame> {
+    let k = max_cycles as i32;
+    let mut frequencies = Vec::new();
+    for ky in 0..=k {
+        for kx in -k..=k {
+            // one of each (k, -k) pair, as they give the same modes
+            if (ky == 0 && kx <= 0) || kx * kx + ky * ky > k * k {
+                continue;
+            }
+            frequencies.push((kx, ky));
+        }
+    }
+    frequencies.sort_by_key(|&(kx, ky)| kx * kx + ky * ky);
+    frequencies.into_iter()
+        .flat_map(|(kx, ky)| [fourier(kx, ky, false, size), fourier(kx, ky, true, size)])
+        .collect()
+}
+
+/// Coefficient of each mode of `basis` in `frame`, i.e., the inner product of
+/// the two divided by that of the mode with itself. This is exact for bases
+/// which are orthogonal on the frame's pixels, and a close approximation for
+/// Zernike polynomials on all but very small pupils.
+pub fn project(frame: &Frame, basis: &[Frame]) -> Result<Vec<f64>> {
+    basis.iter().enumerate().map(|(i, mode)| {
+        check_shape(frame, mode, i)?;
+        let norm = dot(mode.data(), mode.data());
+        if norm == 0.0 {
+            return Err(format!("mode {i} is zero everywhere").into());
+        }
+        Ok(dot(frame.data(), mode.data()) / norm)
+    }).collect()
+}
+
+/// `project()` over only the pixels in `mask`. Modes are not
+/// re-orthogonalised over the mask, so the result is only exact if they
+/// remain orthogonal over it.
+pub fn project_masked(frame: &Frame, basis: &[Frame], mask: &Mask) -> Result<Vec<f64>> {
+    let values = mask.select(frame)?;
+    basis.iter().enumerate().map(|(i, mode)| {
+        check_shape(frame, mode, i)?;
+        let mode = mask.select(mode)?;
+        let norm = dot(&mode, &mode);
+        if norm == 0.0 {
+            return Err(format!("mode {i} is zero everywhere in the mask").into());
+        }
+        Ok(dot(&values, &mode) / norm)
+    }).collect()
+}
+
+/// Sum of the modes of `basis` weighted by `coefficients`.
+pub fn combine(coefficients: &[f64], basis: &[Frame]) -> Result<Frame> {
+    if coefficients.len() != basis.len() {
+        return Err(format!("{} coefficients for {} modes", coefficients.len(), basis.len()).into());
+    }
+    let Some(first) = basis.first() else {
+        return Err("no modes to combine".into());
+    };
+    let mut frame = Frame::zeros(first.width(), first.height());
+    for (i, (&c, mode)) in coefficients.iter().zip(basis).enumerate() {
+        check_shape(&frame, mode, i)?;
+        for (value, &m) in frame.data_mut().iter_mut().zip(mode.data()) {
+            *value += (c * m as f64) as f32;
+        }
+    }
+    Ok(frame)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f64 {
+    a.iter().zip(b).map(|(&a, &b)| a as f64 * b as f64).sum()
+}
+
+fn check_shape(frame: &Frame, mode: &Frame, i: usize) -> Result<()> {
+    if (frame.width(), frame.height()) != (mode.width(), mode.height()) {
+        return Err(format!(
+            "mode {i} is {}x{}, frame is {}x{}",
+            mode.width(), mode.height(), frame.width(), frame.height()
+        ).into());
+    }
+    Ok(())
+}
+
+/// Polar coordinates of the centre of pixel (x, y), with the pupil of radius
+/// 1 inscribed in the frame.
+fn polar(x: usize, y: usize, size: usize) -> (f64, f64) {
+    let half = size as f64 / 2.0;
+    let (x, y) = ((x as f64 + 0.5 - half) / half, (y as f64 + 0.5 - half) / half);
+    (x.hypot(y), y.atan2(x))
+}
+
+/// Zernike radial polynomial R_n^m(r), for m <= n with n - m even.
+fn radial(n: usize, m: usize, r: f64) -> f64 {
+    let factorial = |k: usize| (1..=k).map(|i| i as f64).product::<f64>();
+    (0..=(n - m) / 2).map(|k| {
+        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+        sign * factorial(n - k)
+            / (factorial(k) * factorial((n + m) / 2 - k) * factorial((n - m) / 2 - k))
+            * r.powi((n - 2 * k) as i32)
+    }).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combine, fourier_basis, noll_to_nm, project, project_masked, radial, zernike, zernike_basis};
+    use crate::frame::{Frame, Mask};
+
+    #[test]
+    fn follows_noll_ordering(){
+        let expected = [(0, 0), (1, 1), (1, -1), (2, 0), (2, -2), (2, 2), (3, -1), (3, 1), (3, -3), (3, 3), (4, 0)];
+        for (j, nm) in (1..).zip(expected) {
+            assert_eq!(noll_to_nm(j), nm, "j = {j}");
+        }
+        assert!((radial(2, 0, 0.5) - (2.0 * 0.25 - 1.0)).abs() < 1e-12);
+        assert!((radial(4, 0, 1.0) - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn projects_onto_bases(){
+        let defocus = zernike(4, 128);
+        let pupil: Vec<f32> = defocus.data().iter().copied().filter(|&v| v != 0.0).collect();
+        let mean = pupil.iter().sum::<f32>() / pupil.len() as f32;
+        let variance = pupil.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / pupil.len() as f32;
+        assert!(mean.abs() < 0.02 && (variance - 1.0).abs() < 0.02, "{mean} {variance}");
+
+        let basis = zernike_basis(15, 64);
+        let coefficients: Vec<f64> = (0..15).map(|i| i as f64 / 10.0).collect();
+        let frame = combine(&coefficients, &basis).unwrap();
+        for (got, want) in project(&frame, &basis).unwrap().iter().zip(&coefficients) {
+            assert!((got - want).abs() < 0.02, "{got} {want}");
+        }
+
+        let basis = fourier_basis(3, 16);
+        assert_eq!(basis.len(), 2 * 14);
+        let frame = combine(&(0..basis.len()).map(|i| i as f64).collect::<Vec<_>>(), &basis).unwrap();
+        for (i, got) in project(&frame, &basis).unwrap().iter().enumerate() {
+            assert!((got - i as f64).abs() < 1e-3, "{i} {got}");
+        }
+
+        assert!(project(&Frame::zeros(8, 8), &basis).is_err());
+        assert!(combine(&[1.0], &basis).is_err());
+
+        // dead pixels outside the mask don't leak into the coefficients
+        let basis = zernike_basis(6, 64);
+        let mut frame = combine(&[0.0, 1.0, 0.0, 0.0, 0.0, 0.0], &basis).unwrap();
+        frame.data_mut()[0] = 1e6;
+        let mask = Mask::circular(64);
+        let tip = project_masked(&frame, &basis, &mask).unwrap();
+        assert!((tip[1] - 1.0).abs() < 0.02 && tip[3].abs() < 0.02, "{tip:?}");
+        assert!(project_masked(&frame, &basis, &Mask::circular(8)).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashSet;
+
+use crate::{sanitize, Milk, StreamName, Result};
+
+/// A sequence of milk image operations, checked as a whole before anything
+/// is sent: every image must be made (or declared with `existing()`) before
+/// it is used, and every name must be a valid stream name. Steps are sent in
+/// order as batches, with `sync()` marking the points where the pipeline
+/// waits for milk to catch up.
+///
+/// Image names get the session's name prefix (see
+/// `MilkBuilder::name_prefix()`) when the pipeline is run. Steps using
+/// commands from optional milk modules are sent with `Milk::cmd_from()`, so
+/// a missing module is reported as `ModuleUnavailable`.
+///
+/// # Example
+/// ```
+/// use milkrs::{Milk, Pipeline};
+/// let pipeline = Pipeline::new()
+///     .gen_gauss("src", 64, 64, 4.0)
+///     .gen_gauss("kern", 64, 64, 2.0)
+///     .convolve("src", "kern", "out")
+///     .sync()
+///     .to_shm("out", "outs");
+/// assert!(Pipeline::new().convolve("src", "kern", "out").compile().is_err());
+///
+/// let mut milk = Milk::new().unwrap();
+/// pipeline.run(&mut milk)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+/// Commands to send together, each with the module providing it (if any).
+type Batch = Vec<(String, Option<&'static str>)>;
+
+#[derive(Debug, Clone)]
+enum Step {
+    /// an image which exists before the pipeline runs
+    Existing(String),
+    Command {
+        /// the command, with `{0}`, `{1}`... standing for `names`
+        template: String,
+        names: Vec<String>,
+        /// how many of `names` (from the start) are inputs; the rest are outputs
+        inputs: usize,
+        /// whether the outputs are deleted rather than made
+        removes: bool,
+        /// the milk module providing the command, if not built in
+        module: Option<&'static str>,
+        /// an argument passed as it is in place of `{literal}`, after the
+        /// names are substituted, or why it can't be passed to milk
+        literal: Option<std::result::Result<String, String>>,
+    },
+    Sync,
+}
+
+impl Pipeline {
+    /// Empty pipeline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare an image which already exists in the session, so later steps
+    /// may use it.
+    pub fn existing(mut self, name: &str) -> Self {
+        self.steps.push(Step::Existing(name.to_string()));
+        self
+    }
+
+    /// Make a `width` x `height` image of zeros (`mk2Dim`).
+    pub fn mk2d(self, name: &str, width: usize, height: usize) -> Self {
+        self.step(&format!("mk2Dim {{0}} {width} {height}"), &[], &[name])
+    }
+
+    /// Make a `width` x `height` image of a centred Gaussian with full width
+    /// at half maximum `fwhm` pixels (`mkgauss`, from milk's image_gen
+    /// module).
+    pub fn gen_gauss(self, name: &str, width: usize, height: usize, fwhm: f64) -> Self {
+        self.command(Some("image_gen"), &format!("mkgauss {{0}} {width} {height} {fwhm}"), &[], &[name])
+    }
+
+    /// Load a FITS file as an image (`loadfits`).
+    pub fn load_fits(mut self, path: &str, name: &str) -> Self {
+        // a path which can't be quoted is left for compile() to report
+        self.steps.push(Step::Command {
+            template: "loadfits {literal} {0}".to_string(),
+            names: vec![name.to_string()],
+            inputs: 0,
+            removes: false,
+            module: None,
+            literal: Some(sanitize::quote(path).map_err(|e| e.to_string())),
+        });
+        self
+    }
+
+    /// Connect to an existing shared memory stream as an image of the same
+    /// name (`readshmim`).
+    pub fn read_shm(self, stream: &str) -> Self {
+        self.step("readshmim {0}", &[], &[stream])
+    }
+
+    /// Convolve `input` with `kernel` into a new image `output` (`fconv`,
+    /// from milk's image_filter module).
+    pub fn convolve(self, input: &str, kernel: &str, output: &str) -> Self {
+        self.command(Some("image_filter"), "fconv {0} {1} {2}", &[input, kernel], &[output])
+    }
+
+    /// Copy `image` to the shared memory stream `stream` (`imcp2shm`), which
+    /// can then be used as an image too.
+    pub fn to_shm(self, image: &str, stream: &str) -> Self {
+        self.step("imcp2shm {0} {1}", &[image], &[stream])
+    }
+
+    /// Delete `image` (`rmim`). Later steps may not use it.
+    pub fn remove(mut self, image: &str) -> Self {
+        self.steps.push(Step::Command {
+            template: "rmim {0}".to_string(),
+            names: vec![image.to_string()],
+            inputs: 1,
+            removes: true,
+            module: None,
+            literal: None,
+        });
+        self
+    }
+
+    /// Wait here for milk to finish every step so far before sending more.
+    pub fn sync(mut self) -> Self {
+        self.steps.push(Step::Sync);
+        self
+    }
+
+    /// Any other command, written with `{0}`, `{1}`... standing for the
+    /// images in `inputs` followed by those in `outputs`.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Pipeline;
+    /// let batches = Pipeline::new()
+    ///     .existing("a")
+    ///     .step("imcp2shm {0} {1}", &["a"], &["b"])
+    ///     .compile()?;
+    /// assert_eq!(batches, [vec!["imcp2shm a b".to_string()]]);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn step(self, template: &str, inputs: &[&str], outputs: &[&str]) -> Self {
+        self.command(None, template, inputs, outputs)
+    }
+
+    fn command(mut self, module: Option<&'static str>, template: &str, inputs: &[&str], outputs: &[&str]) -> Self {
+        self.steps.push(Step::Command {
+            template: template.to_string(),
+            names: inputs.iter().chain(outputs).map(|name| name.to_string()).collect(),
+            inputs: inputs.len(),
+            removes: false,
+            module,
+            literal: None,
+        });
+        self
+    }
+
+    /// Check the pipeline and turn it into batches of commands, to be sent
+    /// one after the other with a sync after each.
+    pub fn compile(&self) -> Result<Vec<Vec<String>>> {
+        Ok(self.compile_with("")?.into_iter()
+            .map(|batch| batch.into_iter().map(|(command, _)| command).collect())
+            .collect())
+    }
+
+    /// Check the pipeline and run it in `milk`, returning once milk has
+    /// finished the last step. Nothing is sent if the check fails.
+    pub fn run(&self, milk: &mut Milk) -> Result<()> {
+        let batches = self.compile_with(&milk.prefixed(""))?;
+        for batch in batches {
+            let mut plain = Vec::new();
+            for (command, module) in &batch {
+                match module {
+                    Some(module) => {
+                        if !plain.is_empty() {
+                            milk.cmds(std::mem::take(&mut plain))?;
+                        }
+                        milk.cmd_from(module, command)?;
+                    }
+                    None => plain.push(command.as_str()),
+                }
+            }
+            if !plain.is_empty() {
+                milk.cmds(plain)?;
+            }
+            milk.sync()?;
+        }
+        Ok(())
+    }
+
+    fn compile_with(&self, prefix: &str) -> Result<Vec<Batch>> {
+        let mut defined = HashSet::new();
+        let mut batches = vec![Vec::new()];
+        for (i, step) in self.steps.iter().enumerate() {
+            let fail = |e: String| format!("pipeline step {}: {e}", i + 1);
+            match step {
+                Step::Existing(name) => {
+                    StreamName::new(name).map_err(|e| fail(e.to_string()))?;
+                    defined.insert(name.as_str());
+                }
+                Step::Command { template, names, inputs, removes, module, literal } => {
+                    for name in names {
+                        StreamName::new(name).map_err(|e| fail(e.to_string()))?;
+                    }
+                    let mut command = template.clone();
+                    for (n, name) in names.iter().enumerate() {
+                        command = command.replace(&format!("{{{n}}}"), &format!("{prefix}{name}"));
+                    }
+                    if let Some(literal) = literal {
+                        command = command.replace("{literal}", literal.as_ref().map_err(|e| fail(e.clone()))?);
+                    }
+                    sanitize::validate(&command).map_err(|e| fail(e.to_string()))?;
+                    if let Some(name) = names[..*inputs].iter().find(|name| !defined.contains(name.as_str())) {
+                        return Err(fail(format!("`{command}` uses `{name}` before it is made")).into());
+                    }
+                    for name in &names[*inputs..] {
+                        defined.insert(name.as_str());
+                    }
+                    if *removes {
+                        for name in names {
+                            defined.remove(name.as_str());
+                        }
+                    }
+                    batches.last_mut().expect("never empty").push((command, *module));
+                }
+                Step::Sync => {
+                    if !batches.last().expect("never empty").is_empty() {
+                        batches.push(Vec::new());
+                    }
+                }
+            }
+        }
+        batches.retain(|batch| !batch.is_empty());
+        Ok(batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pipeline;
+    use crate::Milk;
+
+    #[test]
+    fn checks_wiring(){
+        let pipeline = Pipeline::new()
+            .gen_gauss("src", 8, 8, 2.0)
+            .read_shm("kern")
+            .sync()
+            .sync()
+            .convolve("src", "kern", "out")
+            .to_shm("out", "outs")
+            .remove("src");
+        assert_eq!(pipeline.compile().unwrap(), [
+            vec!["mkgauss src 8 8 2".to_string(), "readshmim kern".to_string()],
+            vec!["fconv src kern out".to_string(), "imcp2shm out outs".to_string(), "rmim src".to_string()],
+        ]);
+        let error = pipeline.clone().convolve("src", "kern", "out2").compile().unwrap_err();
+        assert!(error.to_string().contains("step 8") && error.to_string().contains("`src`"), "{error}");
+        assert!(Pipeline::new().mk2d("bad name", 8, 8).compile().is_err());
+        assert!(Pipeline::new().load_fits("a\"b.fits", "im").compile().is_err());
+        // would otherwise pass as three arguments, `"a"`, `b` and `"c.fits"`
+        let error = Pipeline::new().load_fits("\"a\" b \"c.fits", "im").compile().unwrap_err();
+        assert!(error.to_string().starts_with("pipeline step 1: "), "{error}");
+        assert!(Pipeline::new().existing("a").to_shm("a", "b").compile().is_ok());
+    }
+
+    #[test]
+    fn runs_with_the_session_prefix(){
+        let mut milk = Milk::builder().dry_run(true).name_prefix("t1_").build().unwrap();
+        Pipeline::new().mk2d("im", 8, 8).to_shm("im", "ims").run(&mut milk).unwrap();
+        assert_eq!(milk.recorded_commands().unwrap(), ["mk2Dim t1_im 8 8", "imcp2shm t1_im t1_ims"]);
+        // paths are passed as they are, even if they look like placeholders
+        Pipeline::new().load_fits("/data/{0}.fits", "psf").run(&mut milk).unwrap();
+        assert_eq!(milk.recorded_commands().unwrap()[2], "loadfits \"/data/{0}.fits\" t1_psf");
+        let mut milk = Milk::builder().dry_run(true).build().unwrap();
+        assert!(Pipeline::new().to_shm("im", "ims").run(&mut milk).is_err());
+        assert!(milk.recorded_commands().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+//! Per-pixel data quality flags (saturated, dead, NaN, known bad), for
+//! monitoring camera streams.
+use crate::frame::{Frame, Mask};
+use crate::Result;
+
+/// Thresholds and known bad pixels to check frames against with `check()`.
+///
+/// # Example
+/// ```
+/// use milkrs::frame::Frame;
+/// use milkrs::quality::{FlagMap, QualityCheck};
+/// let bad = Frame::new(2, 2, vec![0.0, 0.0, 0.0, 1.0]).unwrap();
+/// let check = QualityCheck::new().saturation(65000.0).dead_below(1.0).bad_pixels(&bad);
+/// let frame = Frame::new(2, 2, vec![65535.0, 0.0, f32::NAN, 500.0]).unwrap();
+/// let flags = check.check(&frame).unwrap();
+/// assert_eq!(flags.get(0, 0), Some(FlagMap::SATURATED));
+/// assert_eq!(flags.counts().flagged, 4);
+/// assert_eq!(flags.good().count(), 0);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct QualityCheck {
+    saturation: Option<f32>,
+    dead_below: Option<f32>,
+    bad: Option<Mask>,
+}
+
+impl QualityCheck {
+    /// Check with nothing configured, which only flags NaNs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flag pixels at or above `level` as saturated.
+    pub fn saturation(mut self, level: f32) -> Self {
+        self.saturation = Some(level);
+        self
+    }
+
+    /// Flag pixels below `level` as dead.
+    pub fn dead_below(mut self, level: f32) -> Self {
+        self.dead_below = Some(level);
+        self
+    }
+
+    /// Flag the pixels which are non-zero in `map` (e.g., a bad pixel map
+    /// loaded from FITS) as bad in every frame.
+    pub fn bad_pixels(mut self, map: &Frame) -> Self {
+        self.bad = Some(Mask::from_frame(map));
+        self
+    }
+
+    /// Flags for each pixel of `frame`.
+    pub fn check(&self, frame: &Frame) -> Result<FlagMap> {
+        if let Some(bad) = &self.bad {
+            if (bad.width(), bad.height()) != (frame.width(), frame.height()) {
+                return Err(format!(
+                    "{}x{} bad pixel map doesn't fit {}x{} frame",
+                    bad.width(), bad.height(), frame.width(), frame.height()
+                ).into());
+            }
+        }
+        let flags = frame.data().iter().enumerate().map(|(i, &v)| {
+            let mut flags = 0;
+            if v.is_nan() {
+                flags |= FlagMap::NAN;
+            }
+            if self.saturation.is_some_and(|level| v >= level) {
+                flags |= FlagMap::SATURATED;
+            }
+            if self.dead_below.is_some_and(|level| v < level) {
+                flags |= FlagMap::DEAD;
+            }
+            if self.bad.as_ref().is_some_and(|bad| bad.bits()[i]) {
+                flags |= FlagMap::BAD;
+            }
+            flags
+        }).collect();
+        Ok(FlagMap { width: frame.width(), height: frame.height(), flags })
+    }
+}
+
+/// Quality flags of each pixel of a frame, from `QualityCheck::check()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlagMap {
+    width: usize,
+    height: usize,
+    flags: Vec<u8>,
+}
+
+/// Number of pixels with each flag in a `FlagMap`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlagCounts {
+    pub saturated: usize,
+    pub dead: usize,
+    pub nan: usize,
+    pub bad: usize,
+    /// pixels with any flag
+    pub flagged: usize,
+}
+
+impl FlagMap {
+    pub const SATURATED: u8 = 1;
+    pub const DEAD: u8 = 2;
+    pub const NAN: u8 = 4;
+    pub const BAD: u8 = 8;
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Flags of pixel (x, y), or None if out of bounds.
+    pub fn get(&self, x: usize, y: usize) -> Option<u8> {
+        (x < self.width && y < self.height).then(|| self.flags[y * self.width + x])
+    }
+
+    /// Flags of every pixel, row by row.
+    pub fn flags(&self) -> &[u8] {
+        &self.flags
+    }
+
+    pub fn counts(&self) -> FlagCounts {
+        let mut counts = FlagCounts::default();
+        for &flags in &self.flags {
+            counts.saturated += (flags & Self::SATURATED != 0) as usize;
+            counts.dead += (flags & Self::DEAD != 0) as usize;
+            counts.nan += (flags & Self::NAN != 0) as usize;
+            counts.bad += (flags & Self::BAD != 0) as usize;
+            counts.flagged += (flags != 0) as usize;
+        }
+        counts
+    }
+
+    /// Mask of the pixels without any flag, for the `_masked` statistics.
+    pub fn good(&self) -> Mask {
+        Mask::new(self.width, self.height, self.flags.iter().map(|&flags| flags == 0).collect())
+            .expect("same size as the map")
+    }
+
+    /// The flags as a frame, e.g., to publish as an auxiliary stream.
+    pub fn to_frame(&self) -> Frame {
+        Frame::new(self.width, self.height, self.flags.iter().map(|&flags| flags as f32).collect())
+            .expect("same size as the map")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlagCounts, FlagMap, QualityCheck};
+    use crate::frame::Frame;
+
+    #[test]
+    fn flags_pixels(){
+        let frame = Frame::new(3, 2, vec![10.0, 4095.0, -1.0, f32::NAN, 50.0, 5000.0]).unwrap();
+        let flags = QualityCheck::new().check(&frame).unwrap();
+        assert_eq!(flags.flags(), &[0, 0, 0, FlagMap::NAN, 0, 0]);
+
+        let bad = Frame::new(3, 2, vec![0.0, 0.0, 0.0, 0.0, 1.0, 1.0]).unwrap();
+        let check = QualityCheck::new().saturation(4095.0).dead_below(0.0).bad_pixels(&bad);
+        let flags = check.check(&frame).unwrap();
+        assert_eq!(flags.flags(), &[0, 1, 2, 4, 8, 9]);
+        assert_eq!(flags.counts(), FlagCounts { saturated: 2, dead: 1, nan: 1, bad: 2, flagged: 5 });
+        assert_eq!(flags.good().bits(), &[true, false, false, false, false, false]);
+        assert_eq!(flags.to_frame().data(), &[0.0, 1.0, 2.0, 4.0, 8.0, 9.0]);
+        assert_eq!(flags.get(2, 1), Some(9));
+        assert_eq!(flags.get(3, 0), None);
+        assert!(check.check(&Frame::zeros(2, 3)).is_err());
+    }
+}
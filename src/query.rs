@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+/// Placeholder in a `Milk::query()` command which is replaced by the path of
+/// the file the result is written to.
+pub const RESULT_PLACEHOLDER: &str = "{result}";
+
+/// What a `Milk::query()` command writes and how to read it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResultSpec {
+    kind: ResultKind,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultKind {
+    F64,
+    I64,
+    String,
+    Vector,
+}
+
+/// A value read back by `Milk::query()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryValue {
+    F64(f64),
+    I64(i64),
+    String(String),
+    Vector(Vec<f64>),
+}
+
+impl ResultSpec {
+    /// The command writes a single floating point number.
+    pub fn f64() -> Self {
+        Self::new(ResultKind::F64)
+    }
+
+    /// The command writes a single integer.
+    pub fn i64() -> Self {
+        Self::new(ResultKind::I64)
+    }
+
+    /// The command writes some text, which is returned without its trailing
+    /// newline.
+    pub fn string() -> Self {
+        Self::new(ResultKind::String)
+    }
+
+    /// The command writes whitespace-separated numbers.
+    pub fn vector() -> Self {
+        Self::new(ResultKind::Vector)
+    }
+
+    /// Read the result from `path` instead of a temporary file, for commands
+    /// which always write to the same place. The file is still deleted once
+    /// it has been read.
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
+
+    fn new(kind: ResultKind) -> Self {
+        Self { kind, file: None }
+    }
+
+    /// The file to read the result from, given the session's temporary one.
+    pub(crate) fn path<'a>(&'a self, temp: &'a Path) -> &'a Path {
+        self.file.as_deref().unwrap_or(temp)
+    }
+
+    /// Parse the contents of the result file.
+    pub(crate) fn parse(&self, contents: &str) -> Result<QueryValue> {
+        let text = contents.trim();
+        let bad = |e: &dyn std::fmt::Display| format!("couldn't parse query result {text:?}: {e}");
+        Ok(match self.kind {
+            ResultKind::F64 => QueryValue::F64(text.parse().map_err(|e| bad(&e))?),
+            ResultKind::I64 => QueryValue::I64(text.parse().map_err(|e| bad(&e))?),
+            ResultKind::String => QueryValue::String(contents.strip_suffix('\n').unwrap_or(contents).to_string()),
+            ResultKind::Vector => QueryValue::Vector(
+                text.split_whitespace()
+                    .map(|word| word.parse().map_err(|e| bad(&e)))
+                    .collect::<std::result::Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+impl QueryValue {
+    /// The value as a number, if it is one (integers are converted).
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::F64(value) => Some(*value),
+            Self::I64(value) => Some(*value as f64),
+            _ => None,
+        }
+    }
+
+    /// The value, if it is an integer.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::I64(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value, if it is text.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value, if it is a vector.
+    pub fn as_vector(&self) -> Option<&[f64]> {
+        match self {
+            Self::Vector(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueryValue, ResultSpec};
+
+    #[test]
+    fn parses_results(){
+        assert_eq!(ResultSpec::f64().parse("0.5\n").unwrap(), QueryValue::F64(0.5));
+        assert_eq!(ResultSpec::i64().parse(" -3\n").unwrap().as_i64(), Some(-3));
+        assert_eq!(ResultSpec::string().parse("a b \n").unwrap().as_str(), Some("a b "));
+        let vector = ResultSpec::vector().parse("1 2.5\n-3\n").unwrap();
+        assert_eq!(vector.as_vector(), Some(&[1.0, 2.5, -3.0][..]));
+        assert_eq!(ResultSpec::vector().parse("").unwrap().as_vector(), Some(&[][..]));
+        assert!(ResultSpec::i64().parse("0.5").is_err());
+        assert!(ResultSpec::f64().parse("").is_err());
+        assert!(ResultSpec::vector().parse("1 x").is_err());
+    }
+}
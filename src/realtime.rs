@@ -0,0 +1,218 @@
+//! Helpers for running low-latency loops alongside milk.
+//!
+//! # Loop timing
+//!
+//! `LoopTimer` measures each iteration of a frame-driven loop against its
+//! deadline, keeping a latency histogram and counting overruns.
+//!
+//! # Memory locking
+//!
+//! A page fault in the middle of a kHz loop costs far more than the loop's
+//! budget. `lock_memory()` locks every page the process has mapped, and every
+//! page it maps from then on, into RAM. Call it once at startup, after the
+//! big allocations are made but before the loop starts.
+//!
+//! Locking needs either the `CAP_IPC_LOCK` capability or a large enough
+//! `RLIMIT_MEMLOCK`, which is usually only 8 MiB by default. Raise it for the
+//! user running the loop, e.g., in `/etc/security/limits.d/rtc.conf`:
+//! ```text
+//! rtc  -  memlock  unlimited
+//! ```
+//! or with `LimitMEMLOCK=infinity` in a systemd unit. Check the current limit
+//! with `ulimit -l`.
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::histogram::FrameHistogram;
+
+/// Number of histogram bins used by `LoopTimer`, spanning zero to four
+/// times the deadline.
+const LATENCY_BINS: usize = 1000;
+
+/// Lock all current and future pages of this process into RAM (see the
+/// module docs for the limits this needs).
+///
+/// # Example
+/// ```
+/// use milkrs::realtime;
+/// if let Err(e) = realtime::lock_memory() {
+///     eprintln!("running without locked memory: {e}");
+/// }
+/// # realtime::unlock_memory().unwrap();
+/// ```
+pub fn lock_memory() -> io::Result<()> {
+    // SAFETY: mlockall has no memory safety preconditions
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Undo `lock_memory()`.
+pub fn unlock_memory() -> io::Result<()> {
+    // SAFETY: munlockall has no memory safety preconditions
+    if unsafe { libc::munlockall() } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Times the iterations of a real-time loop: how long each one took from
+/// `begin()` (e.g., a new frame arriving) to `end()` (the result being
+/// published), and how many took longer than the deadline.
+///
+/// # Example
+/// ```
+/// use milkrs::realtime::LoopTimer;
+/// use std::time::Duration;
+/// let mut timer = LoopTimer::new(Duration::from_micros(500));  // 2 kHz
+/// for _ in 0..100 {
+///     // wait for the next frame here
+///     timer.begin();
+///     // process it here
+///     if timer.end() > timer.deadline() {
+///         eprintln!("overrun");
+///     }
+/// }
+/// let stats = timer.stats();
+/// assert_eq!(stats.iterations, 100);
+/// println!("p99 latency {:?}", timer.percentile(99.0));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoopTimer {
+    deadline: Duration,
+    started: Option<Instant>,
+    histogram: FrameHistogram,
+    stats: LoopStats,
+}
+
+/// Summary of the iterations timed by a `LoopTimer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LoopStats {
+    /// number of iterations timed
+    pub iterations: u64,
+    /// number of iterations which took longer than the deadline
+    pub overruns: u64,
+    /// shortest iteration
+    pub min: Duration,
+    /// longest iteration
+    pub max: Duration,
+    /// total time spent in iterations
+    pub total: Duration,
+}
+
+impl LoopStats {
+    /// Mean iteration time, or None before the first iteration.
+    pub fn mean(&self) -> Option<Duration> {
+        let iterations = u32::try_from(self.iterations).ok().filter(|&n| n > 0)?;
+        Some(self.total / iterations)
+    }
+}
+
+impl LoopTimer {
+    /// Timer for a loop whose iterations must each take at most `deadline`.
+    pub fn new(deadline: Duration) -> Self {
+        let hi = (4.0 * deadline.as_secs_f64() * 1e6) as f32;
+        Self {
+            deadline,
+            started: None,
+            histogram: FrameHistogram::new(LATENCY_BINS, 0.0, hi.max(1.0)),
+            stats: LoopStats::default(),
+        }
+    }
+
+    /// The deadline each iteration is measured against.
+    pub fn deadline(&self) -> Duration {
+        self.deadline
+    }
+
+    /// Mark the start of an iteration.
+    pub fn begin(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    /// Mark the end of the iteration started by the last `begin()`, and
+    /// return how long it took (zero if `begin()` wasn't called).
+    pub fn end(&mut self) -> Duration {
+        let Some(started) = self.started.take() else {
+            return Duration::ZERO;
+        };
+        let elapsed = started.elapsed();
+        self.record(elapsed);
+        elapsed
+    }
+
+    /// Time one iteration made up of running `f`.
+    pub fn time<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        self.begin();
+        let result = f();
+        self.end();
+        result
+    }
+
+    /// Record an iteration timed some other way.
+    pub fn record(&mut self, elapsed: Duration) {
+        let stats = &mut self.stats;
+        if stats.iterations == 0 || elapsed < stats.min {
+            stats.min = elapsed;
+        }
+        stats.max = stats.max.max(elapsed);
+        stats.total += elapsed;
+        stats.iterations += 1;
+        if elapsed > self.deadline {
+            stats.overruns += 1;
+        }
+        self.histogram.add_values(&[(elapsed.as_secs_f64() * 1e6) as f32]);
+    }
+
+    /// Summary of the iterations timed so far.
+    pub fn stats(&self) -> LoopStats {
+        self.stats
+    }
+
+    /// Histogram of iteration times in microseconds, from zero to four times
+    /// the deadline (longer iterations are counted as out of range).
+    pub fn histogram(&self) -> &FrameHistogram {
+        &self.histogram
+    }
+
+    /// Estimate of the `p`th percentile (0 to 100) of iteration times, to
+    /// within a bin width of the histogram. None before the first iteration.
+    pub fn percentile(&self, p: f32) -> Option<Duration> {
+        let micros = self.histogram.percentile(p)?;
+        Some(Duration::from_secs_f64(micros.max(0.0) as f64 * 1e-6))
+    }
+
+    /// Forget all iterations timed so far.
+    pub fn reset(&mut self) {
+        self.started = None;
+        self.histogram.reset();
+        self.stats = LoopStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoopTimer;
+    use std::time::Duration;
+
+    #[test]
+    fn counts_overruns(){
+        let mut timer = LoopTimer::new(Duration::from_millis(1));
+        for micros in [100, 200, 300, 2000, 9000] {
+            timer.record(Duration::from_micros(micros));
+        }
+        let stats = timer.stats();
+        assert_eq!(stats.iterations, 5);
+        assert_eq!(stats.overruns, 2);
+        assert_eq!(stats.min, Duration::from_micros(100));
+        assert_eq!(stats.max, Duration::from_micros(9000));
+        assert_eq!(stats.mean(), Some(Duration::from_micros(2320)));
+        assert_eq!(timer.histogram().out_of_range(), (0, 1));
+        let median = timer.percentile(50.0).unwrap();
+        assert!(median > Duration::from_micros(200) && median <= Duration::from_micros(304), "{median:?}");
+        assert_eq!(timer.end(), Duration::ZERO);
+        timer.reset();
+        assert_eq!(timer.stats().mean(), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::Milk;
+
+/// Resource usage of a process at one point in time, as reported by
+/// `ResourceMonitor::sample()`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceUsage {
+    /// CPU time used since the previous sample, as a percentage of one core
+    /// (so a busy multi-threaded process can exceed 100). None for the first
+    /// sample.
+    pub cpu_percent: Option<f64>,
+    /// total user plus system CPU time used so far
+    pub cpu_time: Duration,
+    /// resident set size in bytes
+    pub rss_bytes: u64,
+    /// number of threads
+    pub threads: u64,
+    /// voluntary context switches so far, summed over all threads
+    pub voluntary_switches: u64,
+    /// involuntary context switches so far, summed over all threads, e.g.,
+    /// from being preempted - a rising count on a real-time thread is a red
+    /// flag
+    pub involuntary_switches: u64,
+}
+
+/// Samples the CPU, memory and context switch counts of a process from
+/// `/proc`, e.g., to spot a milk module regressing from a supervisor.
+///
+/// # Example
+/// ```
+/// use milkrs::{Milk, ResourceMonitor};
+/// let milk = Milk::new().unwrap();
+/// let mut monitor = ResourceMonitor::for_session(&milk).unwrap();
+/// let usage = monitor.sample()?;
+/// println!("milk is using {} MB", usage.rss_bytes / 1_000_000);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ResourceMonitor {
+    pid: u32,
+    last: Option<(Instant, Duration)>,
+}
+
+impl ResourceMonitor {
+    /// Monitor for the process `pid`.
+    pub fn new(pid: u32) -> Self {
+        Self { pid, last: None }
+    }
+
+    /// Monitor for the milk process behind `milk`, or None for dry-run
+    /// sessions.
+    pub fn for_session(milk: &Milk) -> Option<Self> {
+        milk.pid().map(Self::new)
+    }
+
+    /// The process being monitored.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Read the current usage of the process. Fails (with `NotFound`) once
+    /// the process has gone.
+    pub fn sample(&mut self) -> io::Result<ResourceUsage> {
+        let now = Instant::now();
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.pid))?;
+        let status = fs::read_to_string(format!("/proc/{}/status", self.pid))?;
+        let cpu_time = parse_cpu_ticks(&stat)
+            .map(ticks_to_duration)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "couldn't parse /proc stat"))?;
+        let mut usage = ResourceUsage {
+            cpu_time,
+            rss_bytes: status_field(&status, "VmRSS").unwrap_or(0) * 1024,
+            threads: status_field(&status, "Threads").unwrap_or(0),
+            ..ResourceUsage::default()
+        };
+        // the switch counts in the process status are the main thread's only
+        for task in fs::read_dir(format!("/proc/{}/task", self.pid))? {
+            // threads can exit between listing and reading
+            let Ok(status) = fs::read_to_string(task?.path().join("status")) else {
+                continue;
+            };
+            usage.voluntary_switches += status_field(&status, "voluntary_ctxt_switches").unwrap_or(0);
+            usage.involuntary_switches += status_field(&status, "nonvoluntary_ctxt_switches").unwrap_or(0);
+        }
+        if let Some((then, cpu_then)) = self.last {
+            let wall = now.duration_since(then).as_secs_f64();
+            if wall > 0.0 {
+                let cpu = cpu_time.saturating_sub(cpu_then).as_secs_f64();
+                usage.cpu_percent = Some(100.0 * cpu / wall);
+            }
+        }
+        self.last = Some((now, cpu_time));
+        Ok(usage)
+    }
+}
+
+/// User plus system time in clock ticks, from the contents of
+/// `/proc/<pid>/stat`. The command name can contain spaces and parentheses,
+/// so fields are counted from the last `)`.
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    let mut fields = rest.split_whitespace();
+    // utime and stime are fields 14 and 15, the first after the name being 3
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Leading number of the `key:` line in the contents of a `/proc` status file.
+fn status_field(status: &str, key: &str) -> Option<u64> {
+    status.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    // SAFETY: sysconf has no memory safety preconditions
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let per_second = if per_second > 0 { per_second as u64 } else { 100 };
+    Duration::from_secs(ticks / per_second)
+        + Duration::from_nanos((ticks % per_second) * 1_000_000_000 / per_second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpu_ticks, status_field, ResourceMonitor};
+
+    #[test]
+    fn parses_proc_files(){
+        let stat = "1234 (milk (aol0) x) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 3 0";
+        assert_eq!(parse_cpu_ticks(stat), Some(300));
+        assert_eq!(parse_cpu_ticks("1234 (milk"), None);
+        let status = "Name:\tmilk\nThreads:\t3\nVmRSS:\t  20480 kB\nnonvoluntary_ctxt_switches:\t7\n";
+        assert_eq!(status_field(status, "VmRSS"), Some(20480));
+        assert_eq!(status_field(status, "Threads"), Some(3));
+        assert_eq!(status_field(status, "voluntary_ctxt_switches"), None);
+        assert_eq!(status_field(status, "nonvoluntary_ctxt_switches"), Some(7));
+    }
+
+    #[test]
+    fn samples_own_process(){
+        let mut monitor = ResourceMonitor::new(std::process::id());
+        let first = monitor.sample().unwrap();
+        assert_eq!(first.cpu_percent, None);
+        assert!(first.rss_bytes > 0 && first.threads > 0);
+        let second = monitor.sample().unwrap();
+        assert!(second.cpu_percent.is_some());
+        assert!(second.cpu_time >= first.cpu_time);
+        assert!(ResourceMonitor::new(u32::MAX).sample().is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How persistently to retry operations which can fail transiently, such as
+/// spawning milk or writing to the fifo while milk is being restarted.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use milkrs::{Milk, RetryPolicy};
+/// // up to 5 attempts, waiting 10ms, 20ms, 40ms, then 80ms between them
+/// let milk = Milk::builder()
+///     .retry(RetryPolicy::new(5, Duration::from_millis(10)))
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// To ride out milk failing to start for a while, e.g., while the shm
+/// directory is recreated at boot, retry until a deadline instead, with jitter
+/// so that several programs starting together don't retry in lockstep:
+/// ```
+/// use std::time::Duration;
+/// use milkrs::{Milk, RetryPolicy};
+/// let milk = Milk::builder()
+///     .retry(RetryPolicy::new(u32::MAX, Duration::from_millis(50))
+///         .max_backoff(Duration::from_secs(2))
+///         .jitter(0.5)
+///         .deadline(Duration::from_secs(30)))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Option<Duration>,
+    jitter: f64,
+    deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// Defaults to trying everything exactly once.
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl RetryPolicy {
+    /// Make up to `max_attempts` attempts, sleeping for `backoff` after the
+    /// first failure and doubling the sleep after each subsequent one.
+    pub fn new(max_attempts: u32, backoff: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff,
+            max_backoff: None,
+            jitter: 0.0,
+            deadline: None,
+        }
+    }
+
+    /// Stop doubling the sleep between attempts once it reaches `max_backoff`.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = Some(max_backoff);
+        self
+    }
+
+    /// Shorten each sleep by a random fraction of up to `jitter` (clamped to
+    /// between 0 and 1) of its length.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = if jitter.is_nan() { 0.0 } else { jitter.clamp(0.0, 1.0) };
+        self
+    }
+
+    /// Give up once `deadline` has passed since the first attempt, even if
+    /// there are attempts left. The last sleep is cut short to end at the
+    /// deadline, so there is always one final attempt there.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// A single attempt with no retries.
+    pub fn none() -> Self {
+        Self::new(1, Duration::ZERO)
+    }
+
+    /// Time left before the deadline for operations started at `start`, or
+    /// None if there is no deadline.
+    pub(crate) fn remaining(&self, start: Instant) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_sub(start.elapsed()))
+    }
+
+    /// Run `op` until it succeeds, it fails with an error that `retryable`
+    /// rejects, or the attempts run out. The last error is returned on failure.
+    pub(crate) fn run<T, E>(
+        &self,
+        mut op: impl FnMut() -> Result<T, E>,
+        retryable: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            let remaining = self.remaining(start);
+            match op() {
+                Err(e) if attempt < self.max_attempts
+                    && remaining != Some(Duration::ZERO)
+                    && retryable(&e) =>
+                {
+                    let mut sleep = backoff.mul_f64(1.0 - self.jitter * random_fraction());
+                    if let Some(remaining) = remaining {
+                        sleep = sleep.min(remaining);
+                    }
+                    thread::sleep(sleep);
+                    backoff = backoff.saturating_mul(2);
+                    if let Some(max_backoff) = self.max_backoff {
+                        backoff = backoff.min(max_backoff);
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Pseudo-random number in [0, 1) for jitter. Not remotely cryptographic, it
+/// only has to differ between processes and between calls.
+fn random_fraction() -> f64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let mut x = STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_nanos() as u64)
+            .unwrap_or_default();
+        x = (nanos ^ (process::id() as u64).rotate_left(32)) | 1;
+    }
+    // xorshift64
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x, Ordering::Relaxed);
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn retries_until_success(){
+        let policy = RetryPolicy::new(3, Duration::ZERO);
+        let mut attempts = 0;
+        let result: Result<u32, &str> = policy.run(|| {
+            attempts += 1;
+            if attempts < 3 { Err("not yet") } else { Ok(attempts) }
+        }, |_| true);
+        assert_eq!(result, Ok(3));
+    }
+
+    #[test]
+    fn gives_up_on_fatal_errors(){
+        let policy = RetryPolicy::new(3, Duration::ZERO);
+        let mut attempts = 0;
+        let result: Result<(), &str> = policy.run(|| {
+            attempts += 1;
+            Err("fatal")
+        }, |e| *e != "fatal");
+        assert_eq!(result, Err("fatal"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn gives_up_at_the_deadline(){
+        let policy = RetryPolicy::new(u32::MAX, Duration::from_millis(1))
+            .max_backoff(Duration::from_millis(8))
+            .jitter(1.0)
+            .deadline(Duration::from_millis(50));
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result: Result<(), &str> = policy.run(|| {
+            attempts += 1;
+            Err("not yet")
+        }, |_| true);
+        assert_eq!(result, Err("not yet"));
+        assert!(attempts > 5);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(500), "{elapsed:?}");
+    }
+}
@@ -0,0 +1,117 @@
+//! Checking and quoting of milk commands.
+//!
+//! Everything sent to milk passes through `validate()`, so a malformed
+//! command is rejected up front instead of reaching the fifo, where it could
+//! run as two commands or leave milk's parser waiting for a closing quote.
+//! Arguments built from data (file names especially) should go through
+//! `quote()` or `word()`.
+//!
+//! These are pure functions, fuzzed by the `sanitize` target in `fuzz/`
+//! (`cargo fuzz run sanitize`).
+use crate::Result;
+
+/// Check that `command` is a single line which milk will read as one
+/// command: no control characters other than tabs, and balanced double
+/// quotes.
+pub fn validate(command: &str) -> Result<()> {
+    if let Some(c) = command.chars().find(|c| c.is_control() && *c != '\t') {
+        return Err(format!("command {command:?} contains control character {c:?}").into());
+    }
+    if command.chars().filter(|&c| c == '"').count() % 2 != 0 {
+        return Err(format!("command {command:?} has an unterminated quote").into());
+    }
+    Ok(())
+}
+
+/// `arg` in double quotes, to be passed to milk as a single argument even if
+/// it contains spaces, e.g., a file name. milk has no way of escaping a
+/// double quote, so arguments containing one (or a control character) are
+/// rejected.
+///
+/// # Example
+/// ```
+/// use milkrs::sanitize;
+/// let path = "/data/run 1/psf.fits";
+/// let command = format!("loadfits {} psf", sanitize::quote(path)?);
+/// assert_eq!(command, "loadfits \"/data/run 1/psf.fits\" psf");
+/// assert!(sanitize::quote("a\"b").is_err());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn quote(arg: &str) -> Result<String> {
+    if arg.contains('"') {
+        return Err(format!("argument {arg:?} contains a double quote").into());
+    }
+    if let Some(c) = arg.chars().find(|c| c.is_control()) {
+        return Err(format!("argument {arg:?} contains control character {c:?}").into());
+    }
+    Ok(format!("\"{arg}\""))
+}
+
+/// Check that `arg` can be passed to milk unquoted as a single argument,
+/// e.g., an image name: not empty, and free of whitespace, quotes and
+/// control characters.
+pub fn word(arg: &str) -> Result<&str> {
+    if arg.is_empty() {
+        return Err("argument is empty".into());
+    }
+    if let Some(c) = arg.chars().find(|c| c.is_whitespace() || c.is_control() || *c == '"') {
+        return Err(format!("argument {arg:?} contains {c:?}").into());
+    }
+    Ok(arg)
+}
+
+/// The arguments milk sees in `command`: whitespace-separated words, with
+/// double-quoted sections kept together and their quotes removed.
+///
+/// # Example
+/// ```
+/// use milkrs::sanitize;
+/// assert_eq!(sanitize::split("loadfits \"/tmp/a b.fits\" im"), ["loadfits", "/tmp/a b.fits", "im"]);
+/// ```
+pub fn split(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(current);
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, split, validate, word};
+
+    #[test]
+    fn rejects_malformed_commands(){
+        assert!(validate("mk2Dim im1 64 64").is_ok());
+        assert!(validate("loadfits \"/tmp/a b.fits\"\tim").is_ok());
+        for bad in ["a\nexit", "a\rb", "a\0b", "a\u{1b}[2J", "loadfits \"/tmp/a.fits im", "a\u{85}b"] {
+            assert!(validate(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn quoted_arguments_round_trip(){
+        for arg in ["/tmp/psf.fits", "/data/run 1/psf.fits", "", "ünïcødé ✓", "'single'"] {
+            let command = format!("loadfits {} im", quote(arg).unwrap());
+            assert!(validate(&command).is_ok());
+            assert_eq!(split(&command), ["loadfits", arg, "im"]);
+        }
+        assert!(quote("a\"b").is_err());
+        assert!(quote("a\nb").is_err());
+        assert!(quote("a\tb").is_err());
+        assert_eq!(word("im1").unwrap(), "im1");
+        for bad in ["", "im 1", "im\"1", "im\n1"] {
+            assert!(word(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::cell::Cell;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Name of the file in each scratch directory recording which process owns it.
+const MANIFEST: &str = "manifest";
+
+/// How long a scratch directory without a manifest is left alone by `gc()`,
+/// in case its owner is still setting it up.
+const MANIFEST_GRACE: Duration = Duration::from_secs(60);
+
+/// A private directory holding a session's fifo and the temporary files used
+/// to get results back from milk (see `Milk::sync()` and `Milk::query()`).
+/// It is removed with everything in it when the session is dropped.
+///
+/// Each directory records its owner in a manifest, so directories left behind
+/// by a program which crashed are removed by the next call to `gc()`.
+///
+/// # Example
+/// ```
+/// use milkrs::Milk;
+/// let mut milk = Milk::new().unwrap();
+/// let path = milk.scratch_dir().unwrap().mint("fits");
+/// milk.cmd(&format!("writef2file \"{}\" 1", path.display()))?;
+/// milk.sync()?;
+/// assert!(path.exists());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct ScratchDir {
+    path: PathBuf,
+    minted: Cell<u64>,
+}
+
+impl ScratchDir {
+    /// Create the scratch directory `name` for this process under `root()`.
+    pub(crate) fn create(name: &str) -> io::Result<Self> {
+        let root = root()?;
+        let path = root.join(name);
+        DirBuilder::new().mode(0o700).create(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("couldn't create {}: {e}", path.display())))?;
+        let scratch = Self { path, minted: Cell::new(0) };
+        let pid = std::process::id();
+        let started = start_time(pid).unwrap_or(0);
+        fs::write(scratch.path.join(MANIFEST), format!("{pid} {started}\n"))?;
+        Ok(scratch)
+    }
+
+    /// The directory itself.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A path in the directory which hasn't been handed out before, ending
+    /// in `.extension`. Nothing is created there.
+    pub fn mint(&self, extension: &str) -> PathBuf {
+        let n = self.minted.get() + 1;
+        self.minted.set(n);
+        self.path.join(format!("{n}.{extension}"))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Directory holding the scratch directories of every session run by this
+/// user, e.g., `/tmp/milkrs-1000`. Created if it doesn't exist.
+pub(crate) fn root() -> io::Result<PathBuf> {
+    // SAFETY: geteuid has no memory safety preconditions and can't fail
+    let uid = unsafe { libc::geteuid() };
+    let root = std::env::temp_dir().join(format!("milkrs-{uid}"));
+    match DirBuilder::new().mode(0o700).create(&root) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    // don't use a directory planted by someone else in a shared /tmp
+    let meta = fs::symlink_metadata(&root)?;
+    if !meta.is_dir() || meta.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a directory owned by uid {uid}", root.display()),
+        ));
+    }
+    Ok(root)
+}
+
+/// Remove the scratch directories left behind by programs which have exited
+/// without cleaning up (e.g., by crashing or being killed), returning how many
+/// were removed. Directories of running programs are left alone.
+///
+/// # Example
+/// ```
+/// let removed = milkrs::gc()?;
+/// println!("cleaned up after {removed} sessions");
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn gc() -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(root()?)? {
+        let path = entry?.path();
+        let abandoned = match fs::read_to_string(path.join(MANIFEST)) {
+            Ok(manifest) => match parse_manifest(&manifest) {
+                Some((pid, started)) => start_time(pid) != Some(started),
+                None => true,
+            },
+            // the owner may not have written the manifest yet, or may have
+            // just removed the whole directory
+            Err(_) => fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > MANIFEST_GRACE)),
+        };
+        // another gc may have got there first
+        if abandoned && fs::remove_dir_all(&path).is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Owner pid and start time recorded in a manifest.
+fn parse_manifest(manifest: &str) -> Option<(u32, u64)> {
+    let mut fields = manifest.split_whitespace();
+    let pid = fields.next()?.parse().ok()?;
+    let started = fields.next()?.parse().ok()?;
+    Some((pid, started))
+}
+
+/// When process `pid` started, in clock ticks since boot, or None if there
+/// is no such process. Together with the pid this identifies a process even
+/// once its pid has been reused.
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // starttime is field 22, the first after the name being 3
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gc, parse_manifest, root, start_time, ScratchDir, MANIFEST};
+    use std::fs;
+
+    #[test]
+    fn cleans_up_after_dead_owners(){
+        let scratch = ScratchDir::create(&format!("scratch-test-{}", std::process::id())).unwrap();
+        let minted = scratch.mint("txt");
+        assert_ne!(minted, scratch.mint("txt"));
+        assert!(minted.starts_with(scratch.path()));
+        fs::write(&minted, "1").unwrap();
+
+        let dead = root().unwrap().join("scratch-test-dead");
+        fs::create_dir_all(&dead).unwrap();
+        fs::write(dead.join(MANIFEST), format!("{} 0\n", u32::MAX)).unwrap();
+        assert!(gc().unwrap() >= 1);
+        assert!(!dead.exists());
+        assert!(minted.exists());
+
+        let path = scratch.path().to_path_buf();
+        drop(scratch);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reads_manifests(){
+        assert_eq!(parse_manifest("12 345\n"), Some((12, 345)));
+        assert_eq!(parse_manifest("12"), None);
+        assert!(start_time(std::process::id()).is_some());
+        assert_eq!(start_time(u32::MAX), None);
+    }
+}
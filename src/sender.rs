@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::IoSlice;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use crate::{sanitize, write_slices, MilkBuilder, MilkEvent, Result};
+
+enum Message {
+    Command(String),
+    /// reply once every command before this one has been written
+    Flush(Sender<()>),
+    Stop,
+}
+
+impl std::fmt::Debug for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Command(command) => write!(f, "Command({command:?})"),
+            Self::Flush(_) => f.write_str("Flush"),
+            Self::Stop => f.write_str("Stop"),
+        }
+    }
+}
+
+/// A handle for submitting commands to a Milk session from any thread,
+/// obtained with `Milk::sender()`. Clones share one writer thread, which
+/// writes commands to the fifo in the order they were submitted.
+///
+/// Commands from senders are validated, echoed (in verbose mode) and
+/// recorded in the audit log, but skip the session's interceptors and
+/// buffered mode queue. Write failures are reported to the session's hooks
+/// as `MilkEvent::Command` events with `ok: false`. `Milk::sync()` waits for
+/// every command submitted before it. Once the session is closed or
+/// restarted, existing senders fail.
+///
+/// # Example
+/// ```
+/// use milkrs::Milk;
+/// use std::thread;
+/// let mut milk = Milk::new().unwrap();
+/// let sender = milk.sender()?;
+/// let workers: Vec<_> = (0..4).map(|i| {
+///     let sender = sender.clone();
+///     thread::spawn(move || sender.send(&format!("mk2Dim im{i} 64 64")).unwrap())
+/// }).collect();
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+/// milk.sync()?;  // all four images exist now
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct CommandSender {
+    messages: Sender<Message>,
+}
+
+impl CommandSender {
+    /// Queue `command` to be written to the fifo. Fails if the command isn't
+    /// a single well-formed line, or if the session has been closed.
+    pub fn send(&self, command: &str) -> Result<()> {
+        sanitize::validate(command)?;
+        self.messages.send(Message::Command(command.to_string()))
+            .map_err(|_| "the Milk session this sender belongs to has been closed".into())
+    }
+}
+
+/// The writer thread behind a session's `CommandSender`s.
+pub(crate) struct Writer {
+    messages: Sender<Message>,
+    thread: JoinHandle<()>,
+}
+
+impl Writer {
+    /// Start writing to `fifo_pipe` (a duplicate of the session's) on a new
+    /// thread, with the session's settings.
+    pub(crate) fn start(mut fifo_pipe: File, config: MilkBuilder, id: String, label: String) -> Self {
+        let (messages, received) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for message in received {
+                match message {
+                    Message::Command(command) => {
+                        if config.verbose {
+                            eprintln!("[milk {label}] {command}");
+                        }
+                        let mut slices = [IoSlice::new(command.as_bytes()), IoSlice::new(b"\n")];
+                        let result = write_slices(&mut fifo_pipe, &config, &mut slices);
+                        let error = result.as_ref().err().map(|e| e.to_string());
+                        if let Some(audit) = &config.audit {
+                            audit.record(&id, &label, "command", &[("command", &command)], error.as_deref());
+                        }
+                        config.hooks.emit(MilkEvent::Command { command: &command, ok: result.is_ok() });
+                    }
+                    Message::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                    Message::Stop => break,
+                }
+            }
+        });
+        Self { messages, thread }
+    }
+
+    pub(crate) fn sender(&self) -> CommandSender {
+        CommandSender { messages: self.messages.clone() }
+    }
+
+    /// Wait for every command submitted so far to be written.
+    pub(crate) fn flush(&self) {
+        let (done, finished) = mpsc::channel();
+        if self.messages.send(Message::Flush(done)).is_ok() {
+            let _ = finished.recv();
+        }
+    }
+
+    /// Write every command submitted so far, then stop the thread.
+    pub(crate) fn stop(self) {
+        let _ = self.messages.send(Message::Stop);
+        let _ = self.thread.join();
+    }
+}
@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::io;
+use std::process::ExitStatus;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::{Milk, MilkBuilder, ModuleUnavailable, QueryValue, ResultSpec, Result};
+
+type Job = Box<dyn FnOnce(&mut Option<Milk>) + Send>;
+
+/// An error which can be passed back from the session's thread.
+type SendError = Box<dyn Error + Send + Sync>;
+
+/// A Milk session which can be shared between threads and used through
+/// `&self`, e.g., from GUI callbacks or async tasks, without wrapping it in
+/// `Arc<Mutex<...>>`. Made with `MilkBuilder::build_shared()`.
+///
+/// The session lives on a thread of its own, which runs calls one at a time
+/// in the order they arrive. Clones are handles to the same session, which
+/// is closed by `close()` or once the last handle is dropped (which waits for
+/// the thread to finish). Anything not covered by the methods here can be
+/// done with `with()`.
+///
+/// # Example
+/// ```
+/// use milkrs::Milk;
+/// use std::thread;
+/// let milk = Milk::builder().build_shared()?;
+/// let worker = {
+///     let milk = milk.clone();
+///     thread::spawn(move || milk.cmd("mk2Dim im1 64 64").unwrap())
+/// };
+/// worker.join().unwrap();
+/// milk.sync()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct SharedMilk {
+    inner: Arc<Inner>,
+}
+
+/// The session's thread, shared by every handle.
+#[derive(Debug)]
+struct Inner {
+    jobs: Option<Sender<Job>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SharedMilk {
+    /// Build the session configured by `builder` and move it to a new thread.
+    pub(crate) fn start(builder: MilkBuilder) -> Result<Self> {
+        let mut milk = Some(builder.build()?);
+        let (jobs, received) = mpsc::channel::<Job>();
+        let thread = thread::spawn(move || {
+            for job in received {
+                job(&mut milk);
+            }
+        });
+        Ok(Self { inner: Arc::new(Inner { jobs: Some(jobs), thread: Mutex::new(Some(thread)) }) })
+    }
+
+    /// Run `f` on the session's thread and return its result. Fails if the
+    /// session has been closed.
+    ///
+    /// # Example
+    /// ```
+    /// use milkrs::Milk;
+    /// let milk = Milk::builder().build_shared()?;
+    /// let pid = milk.with(|milk| milk.pid())?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn with<R: Send + 'static>(&self, f: impl FnOnce(&mut Milk) -> R + Send + 'static) -> Result<R> {
+        self.run(move |milk| match milk {
+            Some(milk) => Ok(f(milk)),
+            None => Err("the shared Milk session has been closed".into()),
+        })
+    }
+
+    /// See `Milk::cmd()`.
+    pub fn cmd(&self, command: &str) -> Result<()> {
+        let command = command.to_string();
+        self.try_with(move |milk| milk.cmd(&command))
+    }
+
+    /// See `Milk::cmds()`.
+    pub fn cmds(&self, commands: &[&str]) -> Result<()> {
+        let commands: Vec<String> = commands.iter().map(|command| command.to_string()).collect();
+        self.try_with(move |milk| milk.cmds(commands.iter().map(String::as_str).collect()))
+    }
+
+    /// See `Milk::sync()`.
+    pub fn sync(&self) -> Result<()> {
+        self.try_with(|milk| milk.sync())
+    }
+
+    /// See `Milk::query()`.
+    pub fn query(&self, command: &str, spec: ResultSpec) -> Result<QueryValue> {
+        let command = command.to_string();
+        self.try_with(move |milk| milk.query(&command, spec))
+    }
+
+    /// See `Milk::is_healthy()`. A closed session is unhealthy.
+    pub fn is_healthy(&self) -> bool {
+        self.with(|milk| milk.is_healthy()).unwrap_or(false)
+    }
+
+    /// Close the session for every handle, returning milk's exit status as
+    /// `Milk::close()` does. Fails if it has already been closed.
+    pub fn close(&self) -> Result<Option<ExitStatus>> {
+        self.run(|milk| match milk.take() {
+            Some(milk) => milk.close().map_err(sendable),
+            None => Err("the shared Milk session has been closed".into()),
+        })
+    }
+
+    /// `with()` for fallible calls.
+    fn try_with<R: Send + 'static>(&self, f: impl FnOnce(&mut Milk) -> Result<R> + Send + 'static) -> Result<R> {
+        self.run(move |milk| match milk {
+            Some(milk) => f(milk).map_err(sendable),
+            None => Err("the shared Milk session has been closed".into()),
+        })
+    }
+
+    fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Option<Milk>) -> std::result::Result<R, SendError> + Send + 'static,
+    ) -> Result<R> {
+        let (reply, result) = mpsc::channel();
+        let job: Job = Box::new(move |milk| {
+            let _ = reply.send(f(milk));
+        });
+        let jobs = self.inner.jobs.as_ref().expect("only taken on drop");
+        jobs.send(job).map_err(|_| "the session thread has stopped")?;
+        let result = result.recv().map_err(|_| "the session thread panicked")?;
+        result.map_err(|e| -> Box<dyn Error> { e })
+    }
+}
+
+impl Drop for Inner {
+    /// Close the session once the last handle has gone, and wait for its
+    /// thread to finish.
+    fn drop(&mut self) {
+        if let Some(jobs) = self.jobs.take() {
+            let _ = jobs.send(Box::new(|milk| {
+                if let Some(milk) = milk.take() {
+                    let _ = milk.close();
+                }
+            }));
+        }
+        let thread = self.thread.get_mut().unwrap_or_else(|e| e.into_inner()).take();
+        // a job holding the last handle drops it on the thread itself
+        if let Some(thread) = thread.filter(|thread| thread.thread().id() != thread::current().id()) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// `e`, made sendable to pass it back from the session's thread. The errors
+/// callers look for by downcasting (`io::Error` and `ModuleUnavailable`) are
+/// passed back as they are; anything else as its message.
+fn sendable(e: Box<dyn Error>) -> SendError {
+    let e = match e.downcast::<io::Error>() {
+        Ok(e) => return e,
+        Err(e) => e,
+    };
+    match e.downcast::<ModuleUnavailable>() {
+        Ok(e) => e,
+        Err(e) => e.to_string().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Milk, ResultSpec};
+    use std::io;
+    use std::path::Path;
+    use std::thread;
+
+    #[test]
+    fn shares_a_session_between_threads(){
+        fn send_and_sync<T: Send + Sync>() {}
+        send_and_sync::<super::SharedMilk>();
+        let milk = Milk::builder().build_shared().expect("Failed to start milk");
+        let workers: Vec<_> = (0..4).map(|i| {
+            let milk = milk.clone();
+            thread::spawn(move || milk.cmd(&format!("writef2file \"/tmp/tmp_shared.{i}\" {i}")).is_ok())
+        }).collect();
+        for worker in workers {
+            assert!(worker.join().expect("worker panicked"));
+        }
+        let value = milk.query("writef2file \"{result}\" 3", ResultSpec::i64()).expect("couldn't query");
+        assert_eq!(value.as_i64(), Some(3));
+        assert!(milk.cmd("bad\ncommand").is_err());
+        assert!(milk.is_healthy());
+        assert!(milk.close().expect("couldn't close").expect("no status").success());
+        assert!(milk.cmd("mk2Dim im1 64 64").is_err());
+        assert!(!milk.is_healthy());
+        assert!(Milk::builder().binary("/nonexistent/milk").build_shared().is_err());
+    }
+
+    #[test]
+    fn passes_errors_back_as_they_are(){
+        let milk = Milk::builder().build_shared().expect("Failed to start milk");
+        let e = milk.try_with(|_| -> crate::Result<()> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "milk is busy").into())
+        }).unwrap_err();
+        let e = e.downcast_ref::<io::Error>().expect("not an io::Error");
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn dropping_the_last_handle_closes_the_session(){
+        let milk = Milk::builder().build_shared().expect("Failed to start milk");
+        let pid = milk.with(|milk| milk.pid()).unwrap().expect("not a dry run");
+        let other = milk.clone();
+        drop(milk);
+        assert!(other.is_healthy());
+        drop(other);
+        assert!(!Path::new(&format!("/proc/{pid}")).exists());
+    }
+}
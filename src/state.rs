@@ -0,0 +1,170 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::sanitize;
+
+/// Commands which make an image, and which of their arguments names it.
+const MAKES_IMAGE: &[(&str, usize)] = &[
+    ("mk2Dim", 0),
+    ("mk3Dim", 0),
+    ("mkgauss", 0),
+    ("loadfits", 1),
+    ("readshmim", 0),
+    ("imcp2shm", 1),
+    ("fconv", 2),
+];
+
+/// What a session has set up - its images, loaded modules and variables -
+/// worked out from the commands sent to it, from `Milk::snapshot_state()`.
+///
+/// Images are tracked if made by one of the commands milkrs knows about
+/// (`mk2Dim`, `mk3Dim`, `mkgauss`, `loadfits`, `readshmim`, `imcp2shm` and
+/// `fconv`) and forgotten when deleted with `rmim`. Modules are those loaded
+/// with `mload`, and variables those assigned with `name=value`. Commands
+/// sent through a `CommandSender` are not seen.
+///
+/// A state is saved as the milk script which recreates it, one command per
+/// line.
+///
+/// # Example
+/// ```
+/// use milkrs::Milk;
+/// let mut milk = Milk::new().unwrap();
+/// milk.cmds(vec!["mk2Dim im1 8 8", "mk2Dim im2 8 8", "rmim im1", "gain=0.5"])?;
+/// let state = milk.snapshot_state();
+/// assert_eq!(state.images().collect::<Vec<_>>(), ["im2"]);
+/// let path = std::env::temp_dir().join("milkrs_state_doc.milk");
+/// state.save(&path)?;
+///
+/// let mut fresh = Milk::new().unwrap();
+/// fresh.restore_state(&milkrs::SessionState::load(&path)?)?;
+/// assert_eq!(fresh.snapshot_state(), state);
+/// # std::fs::remove_file(&path)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionState {
+    modules: Vec<String>,
+    variables: Vec<(String, String)>,
+    /// each image and the command which made it, oldest first
+    images: Vec<(String, String)>,
+}
+
+impl SessionState {
+    /// Names of the images, oldest first.
+    pub fn images(&self) -> impl Iterator<Item = &str> {
+        self.images.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Modules loaded, in order.
+    pub fn modules(&self) -> impl Iterator<Item = &str> {
+        self.modules.iter().map(String::as_str)
+    }
+
+    /// Variables and their values, in order of first assignment.
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.variables.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// The commands recreating this state in a fresh session: modules first,
+    /// then variables, then images in the order they were made. An image made
+    /// from another which has since been deleted can't be recreated.
+    pub fn commands(&self) -> Vec<String> {
+        self.modules.iter().map(|module| format!("mload {module}"))
+            .chain(self.variables.iter().map(|(name, value)| format!("{name}={value}")))
+            .chain(self.images.iter().map(|(_, command)| command.clone()))
+            .collect()
+    }
+
+    /// Write the state to `path` as a milk script.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut script = self.commands().join("\n");
+        script.push('\n');
+        fs::write(path, script)
+    }
+
+    /// Read a state written by `save()`, or worked out from any milk script.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_script(&fs::read_to_string(path)?))
+    }
+
+    /// The state a fresh session would be in after running `script`.
+    pub fn from_script(script: &str) -> Self {
+        let mut state = Self::default();
+        for line in script.lines() {
+            state.apply(line);
+        }
+        state
+    }
+
+    /// Names of the images made since `earlier`, newest first. Images which
+    /// existed then but have been remade since are not included.
+    pub(crate) fn images_since(&self, earlier: &SessionState) -> Vec<String> {
+        self.images.iter().rev()
+            .filter(|(name, _)| !earlier.images.iter().any(|(n, _)| n == name))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Update the state for a command milk has been sent.
+    pub(crate) fn apply(&mut self, command: &str) {
+        let command = command.trim();
+        if let Some((name, value)) = assignment(command) {
+            match self.variables.iter_mut().find(|(n, _)| n == name) {
+                Some((_, old)) => *old = value.to_string(),
+                None => self.variables.push((name.to_string(), value.to_string())),
+            }
+            return;
+        }
+        let Some((verb, rest)) = command.split_once(' ') else { return };
+        if verb == "mload" {
+            let module = rest.trim();
+            if !self.modules.iter().any(|m| m == module) {
+                self.modules.push(module.to_string());
+            }
+            return;
+        }
+        let args = sanitize::split(rest);
+        if verb == "rmim" {
+            self.images.retain(|(name, _)| !args.contains(name));
+        } else if let Some(&(_, at)) = MAKES_IMAGE.iter().find(|(v, _)| *v == verb) {
+            let Some(name) = args.get(at) else { return };
+            self.images.retain(|(n, _)| n != name);
+            self.images.push((name.clone(), command.to_string()));
+        }
+    }
+}
+
+/// `name` and `value` of a variable assignment `name=value`.
+fn assignment(command: &str) -> Option<(&str, &str)> {
+    let (name, value) = command.split_once('=')?;
+    let (name, value) = (name.trim(), value.trim());
+    let identifier = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    (identifier && !value.is_empty() && !value.contains(char::is_whitespace)).then_some((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionState;
+
+    #[test]
+    fn tracks_commands(){
+        let state = SessionState::from_script(
+            "mload milkimagegen\nmk2Dim a 8 8\nloadfits \"/tmp/my psf.fits\" psf\nfconv a psf out\n\
+             x = 2\nrmim a\nmk2Dim psf 4 4\nx=3\nmload milkimagegen\nlistim\nwritef2file \"/tmp/a=b\" 1\n",
+        );
+        assert_eq!(state.images().collect::<Vec<_>>(), ["out", "psf"]);
+        assert_eq!(state.modules().collect::<Vec<_>>(), ["milkimagegen"]);
+        assert_eq!(state.variables().collect::<Vec<_>>(), [("x", "3")]);
+        assert_eq!(state.commands(), ["mload milkimagegen", "x=3", "fconv a psf out", "mk2Dim psf 4 4"]);
+        assert_eq!(SessionState::from_script(&state.commands().join("\n")).images().count(), 2);
+
+        let mut later = state.clone();
+        later.apply("imcp2shm out outs");
+        later.apply("mk2Dim psf 8 8");
+        later.apply("mk2Dim tmp 8 8");
+        assert_eq!(later.images_since(&state), ["tmp", "outs"]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::Result;
+
+/// Longest stream name ImageStreamIO can store (its name field is 80 bytes,
+/// including the terminating NUL).
+pub const MAX_STREAM_NAME_LEN: usize = 79;
+
+/// Name of a milk image or shared memory stream, checked against the naming
+/// rules when it is made: 1 to `MAX_STREAM_NAME_LEN` characters, the first a
+/// letter, the rest ASCII letters, digits or underscores. Anything else
+/// either gets truncated by ImageStreamIO or can't be parsed by milk's
+/// command line, and only shows up later as "stream not found".
+///
+/// # Example
+/// ```
+/// use milkrs::StreamName;
+/// let name = StreamName::aol(0, "wfsim")?;
+/// assert_eq!(name.as_str(), "aol0_wfsim");
+/// assert_eq!(name.aol_loop(), Some(0));
+/// assert!(StreamName::new("aol0 wfsim").is_err());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamName(String);
+
+impl StreamName {
+    /// Validate `name` as a stream name.
+    pub fn new(name: &str) -> Result<Self> {
+        let Some(first) = name.chars().next() else {
+            return Err("stream name is empty".into());
+        };
+        if name.len() > MAX_STREAM_NAME_LEN {
+            return Err(format!(
+                "stream name {name:?} is {} bytes long (at most {MAX_STREAM_NAME_LEN} allowed)",
+                name.len(),
+            ).into());
+        }
+        if !first.is_ascii_alphabetic() {
+            return Err(format!("stream name {name:?} must start with a letter").into());
+        }
+        if let Some(c) = name.chars().find(|c| !c.is_ascii_alphanumeric() && *c != '_') {
+            return Err(format!("stream name {name:?} contains {c:?} (only letters, digits and _ allowed)").into());
+        }
+        Ok(Self(name.to_string()))
+    }
+
+    /// Name of a stream belonging to cacao loop `index`, following the
+    /// `aol<index>_<suffix>` convention, e.g., `aol0_wfsim`.
+    pub fn aol(index: u32, suffix: &str) -> Result<Self> {
+        if suffix.is_empty() {
+            return Err("cacao stream suffix is empty".into());
+        }
+        Self::new(&format!("aol{index}_{suffix}"))
+    }
+
+    /// The cacao loop index, if the name follows the `aol<index>_` convention.
+    pub fn aol_loop(&self) -> Option<u32> {
+        let (prefix, suffix) = self.0.split_once('_')?;
+        let digits = prefix.strip_prefix("aol")?;
+        if suffix.is_empty() || digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
+    /// The part after the `aol<index>_` prefix, if the name has one.
+    pub fn aol_suffix(&self) -> Option<&str> {
+        self.aol_loop()?;
+        self.0.split_once('_').map(|(_, suffix)| suffix)
+    }
+
+    /// The name itself.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Path of the stream's file in the shared memory directory `shm_dir`.
+    pub fn shm_path(&self, shm_dir: impl AsRef<Path>) -> PathBuf {
+        shm_dir.as_ref().join(format!("{}.im.shm", self.0))
+    }
+}
+
+impl fmt::Display for StreamName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for StreamName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for StreamName {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Self::new(name)
+    }
+}
+
+impl TryFrom<&str> for StreamName {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(name: &str) -> Result<Self> {
+        Self::new(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamName, MAX_STREAM_NAME_LEN};
+
+    #[test]
+    fn validates_names(){
+        assert!(StreamName::new("dm00disp").is_ok());
+        assert!(StreamName::new(&"a".repeat(MAX_STREAM_NAME_LEN)).is_ok());
+        assert!(StreamName::new(&"a".repeat(MAX_STREAM_NAME_LEN + 1)).is_err());
+        for bad in ["", "0dm", "_dm", "dm disp", "dm.disp", "dm-disp", "dmé"] {
+            assert!(StreamName::new(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn follows_cacao_conventions(){
+        let name: StreamName = "aol12_dmC".parse().unwrap();
+        assert_eq!(name.aol_loop(), Some(12));
+        assert_eq!(name.aol_suffix(), Some("dmC"));
+        assert_eq!(StreamName::aol(12, "dmC").unwrap(), name);
+        assert!(StreamName::aol(0, "").is_err());
+        for other in ["aolx_dmC", "aol_dmC", "aol3", "dm00disp"] {
+            assert_eq!(StreamName::new(other).unwrap().aol_loop(), None, "{other}");
+        }
+        assert_eq!(name.shm_path("/milk/shm").to_str(), Some("/milk/shm/aol12_dmC.im.shm"));
+    }
+}
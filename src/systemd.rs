@@ -0,0 +1,105 @@
+//! Minimal `sd_notify(3)` support, so that a milkrs-managed session can run
+//! as a `Type=notify` systemd unit with `WatchdogSec=` set.
+//!
+//! This speaks the notification protocol directly over the datagram socket
+//! named by `$NOTIFY_SOCKET` instead of linking against libsystemd. When not
+//! running under systemd every function here is a no-op.
+use std::env;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Send a notification `state` (e.g. `"READY=1"`) to the service manager.
+///
+/// Returns `Ok(false)` if there is no service manager to notify.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let path = path.to_string_lossy().into_owned();
+    let addr = socket_addr(&path)?;
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
+
+/// Tell the service manager that startup has finished.
+pub fn ready() -> io::Result<bool> {
+    notify("READY=1")
+}
+
+/// Tell the service manager that the service is shutting down.
+pub fn stopping() -> io::Result<bool> {
+    notify("STOPPING=1")
+}
+
+/// Free-form status line shown by `systemctl status`.
+pub fn status(text: &str) -> io::Result<bool> {
+    notify(&format!("STATUS={}", text.replace('\n', " ")))
+}
+
+/// Keep the watchdog from firing. Only call this while the service is healthy.
+pub fn watchdog() -> io::Result<bool> {
+    notify("WATCHDOG=1")
+}
+
+/// How often [`watchdog`] should be called, or `None` if the watchdog is off.
+///
+/// This is half the `WatchdogSec=` of the unit, as `sd_watchdog_enabled(3)`
+/// recommends.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+fn socket_addr(path: &str) -> io::Result<SocketAddr> {
+    match path.strip_prefix('@') {
+        Some(name) => abstract_addr(name),
+        None => SocketAddr::from_pathname(path),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifies_the_socket(){
+        let path = env::temp_dir().join(format!("milkrs-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        assert!(ready().unwrap());
+        assert!(status("two\nlines").unwrap());
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(!ready().unwrap());
+        let mut buf = [0u8; 64];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=two lines");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::builder::DEFAULT_ROTATION;
+use crate::log::RotatingFile;
+
+/// Appends rows of scalar telemetry to a CSV file which opens in any
+/// spreadsheet. Each row holds the time (seconds since the Unix epoch), a
+/// frame counter and one value per named column. The file is rotated like
+/// `MilkBuilder::stdout_to()`, and every file starts with the header.
+///
+/// # Example
+/// ```
+/// use milkrs::TelemetryWriter;
+/// use milkrs::frame::Frame;
+/// let path = std::env::temp_dir().join("milkrs_telemetry_doc.csv");
+/// # let _ = std::fs::remove_file(&path);
+/// let mut csv = TelemetryWriter::create(&path, &["mean", "max"])?;
+/// let frame = Frame::new(2, 1, vec![1.0, 3.0]).unwrap();
+/// let mean = frame.data().iter().sum::<f32>() / 2.0;
+/// let max = frame.data().iter().copied().fold(f32::MIN, f32::max);
+/// csv.row_now(42, &[mean as f64, max as f64])?;
+/// let text = std::fs::read_to_string(&path)?;
+/// assert!(text.starts_with("time,cnt0,mean,max\n"));
+/// assert!(text.ends_with(",42,2,3\n"));
+/// # std::fs::remove_file(&path)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub struct TelemetryWriter {
+    file: RotatingFile,
+    columns: usize,
+}
+
+impl TelemetryWriter {
+    /// Append to the CSV file at `path`, rotating it with the same defaults
+    /// as milk's output files. The header is written if the file is new.
+    pub fn create(path: impl AsRef<Path>, columns: &[&str]) -> io::Result<Self> {
+        let (max_bytes, keep) = DEFAULT_ROTATION;
+        Self::with_rotation(path, columns, max_bytes, keep)
+    }
+
+    /// Like `create()`, rotating before the file would pass `max_bytes` and
+    /// keeping `keep` old files as `<path>.1` to `<path>.<keep>`.
+    pub fn with_rotation(
+        path: impl AsRef<Path>,
+        columns: &[&str],
+        max_bytes: u64,
+        keep: usize,
+    ) -> io::Result<Self> {
+        let header: Vec<String> = ["time", "cnt0"].iter().chain(columns).map(|name| field(name)).collect();
+        let file = RotatingFile::open(path.as_ref(), max_bytes, keep)?
+            .with_header(header.join(",").as_bytes())?;
+        Ok(Self { file, columns: columns.len() })
+    }
+
+    /// Append a row for frame `cnt0` taken at `time`, with one value per
+    /// column. Non-finite values are written as `NaN`, `inf` or `-inf`.
+    pub fn row(&mut self, time: SystemTime, cnt0: u64, values: &[f64]) -> io::Result<()> {
+        if values.len() != self.columns {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} values for {} columns", values.len(), self.columns),
+            ));
+        }
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let mut line = format!("{time:.6},{cnt0}");
+        for value in values {
+            line.push_str(&format!(",{value}"));
+        }
+        self.file.write_line(line.as_bytes())
+    }
+
+    /// `row()` at the current time.
+    pub fn row_now(&mut self, cnt0: u64, values: &[f64]) -> io::Result<()> {
+        self.row(SystemTime::now(), cnt0, values)
+    }
+}
+
+/// `name` as a CSV field, quoted if it needs to be.
+fn field(name: &str) -> String {
+    if name.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TelemetryWriter;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn writes_rotated_csv(){
+        let dir = format!("/tmp/milkrs_telemetry_test_{}", std::process::id());
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{dir}/loop.csv");
+        let mut csv = TelemetryWriter::with_rotation(&path, &["rms", "gain, loop"], 80, 1).unwrap();
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        csv.row(time, 1, &[0.25, f64::NAN]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "time,cnt0,rms,\"gain, loop\"\n1.500000,1,0.25,NaN\n");
+        csv.row(time, 2, &[0.5, 1.0]).unwrap();
+        csv.row(time, 3, &[0.75, 1.0]).unwrap();
+        assert!(csv.row(time, 4, &[1.0]).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "time,cnt0,rms,\"gain, loop\"\n1.500000,3,0.75,1\n");
+        assert!(fs::read_to_string(format!("{path}.1")).unwrap().ends_with("1.500000,2,0.5,1\n"));
+
+        // reopening appends without repeating the header
+        drop(csv);
+        TelemetryWriter::with_rotation(&path, &["rms", "gain, loop"], 1000, 1).unwrap().row(time, 4, &[1.0, 1.0]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().matches("time,cnt0").count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
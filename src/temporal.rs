@@ -0,0 +1,150 @@
+//! Filters over successive frames of a stream: differences, boxcar averages
+//! and exponential moving averages.
+use std::collections::VecDeque;
+
+use crate::frame::Frame;
+use crate::Result;
+
+/// Which derived frame a `FrameFilter` produces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemporalFilter {
+    /// each frame minus the one before it
+    Difference,
+    /// mean of the last `n` frames
+    Boxcar(usize),
+    /// `alpha * frame + (1 - alpha) * previous output`, with `alpha` in (0, 1]
+    Ema(f32),
+}
+
+/// Applies a `TemporalFilter` to frames pushed one at a time.
+///
+/// # Example
+/// ```
+/// use milkrs::frame::Frame;
+/// use milkrs::temporal::{FrameFilter, TemporalFilter};
+/// let mut boxcar = FrameFilter::new(TemporalFilter::Boxcar(2)).unwrap();
+/// let frame = |v| Frame::new(1, 1, vec![v]).unwrap();
+/// assert_eq!(boxcar.push(&frame(1.0)).unwrap(), None);
+/// assert_eq!(boxcar.push(&frame(3.0)).unwrap(), Some(frame(2.0)));
+/// assert_eq!(boxcar.push(&frame(7.0)).unwrap(), Some(frame(5.0)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct FrameFilter {
+    filter: TemporalFilter,
+    /// previous frames, oldest first: one for differences, up to `n` for
+    /// boxcars, the previous output for moving averages
+    history: VecDeque<Frame>,
+    /// running sum of `history`, for boxcars
+    sum: Vec<f64>,
+}
+
+impl FrameFilter {
+    pub fn new(filter: TemporalFilter) -> Result<Self> {
+        match filter {
+            TemporalFilter::Boxcar(0) => return Err("boxcar needs at least one frame".into()),
+            TemporalFilter::Ema(alpha) if !(alpha > 0.0 && alpha <= 1.0) => {
+                return Err(format!("moving average weight {alpha} is not in (0, 1]").into());
+            }
+            _ => {}
+        }
+        Ok(Self { filter, history: VecDeque::new(), sum: Vec::new() })
+    }
+
+    /// Add the next frame, returning the filtered frame once enough frames
+    /// have been seen (two for differences, `n` for boxcars, one for moving
+    /// averages). Frames must all be the same size.
+    pub fn push(&mut self, frame: &Frame) -> Result<Option<Frame>> {
+        if let Some(last) = self.history.back() {
+            if (last.width(), last.height()) != (frame.width(), frame.height()) {
+                return Err(format!(
+                    "{}x{} frame after {}x{} frames",
+                    frame.width(), frame.height(), last.width(), last.height()
+                ).into());
+            }
+        }
+        let (width, height) = (frame.width(), frame.height());
+        let output = match self.filter {
+            TemporalFilter::Difference => {
+                let output = self.history.pop_front().map(|last| {
+                    let data = frame.data().iter().zip(last.data()).map(|(a, b)| a - b).collect();
+                    Frame::new(width, height, data).expect("same size as the input")
+                });
+                self.history.push_back(frame.clone());
+                output
+            }
+            TemporalFilter::Boxcar(n) => {
+                if self.sum.len() != frame.data().len() {
+                    self.sum = vec![0.0; frame.data().len()];
+                }
+                for (sum, &v) in self.sum.iter_mut().zip(frame.data()) {
+                    *sum += v as f64;
+                }
+                self.history.push_back(frame.clone());
+                if self.history.len() > n {
+                    let oldest = self.history.pop_front().expect("more than n frames");
+                    for (sum, &v) in self.sum.iter_mut().zip(oldest.data()) {
+                        *sum -= v as f64;
+                    }
+                }
+                (self.history.len() == n).then(|| {
+                    let data = self.sum.iter().map(|sum| (sum / n as f64) as f32).collect();
+                    Frame::new(width, height, data).expect("same size as the input")
+                })
+            }
+            TemporalFilter::Ema(alpha) => {
+                let output = match self.history.pop_front() {
+                    None => frame.clone(),
+                    Some(mut last) => {
+                        for (out, &v) in last.data_mut().iter_mut().zip(frame.data()) {
+                            *out += alpha * (v - *out);
+                        }
+                        last
+                    }
+                };
+                self.history.push_back(output.clone());
+                Some(output)
+            }
+        };
+        Ok(output)
+    }
+
+    /// Forget every frame pushed so far.
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.sum.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameFilter, TemporalFilter};
+    use crate::frame::Frame;
+
+    fn frame(values: [f32; 2]) -> Frame {
+        Frame::new(2, 1, values.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn filters_frames(){
+        let mut difference = FrameFilter::new(TemporalFilter::Difference).unwrap();
+        assert_eq!(difference.push(&frame([1.0, 2.0])).unwrap(), None);
+        assert_eq!(difference.push(&frame([4.0, 1.0])).unwrap(), Some(frame([3.0, -1.0])));
+        assert_eq!(difference.push(&frame([4.0, 1.0])).unwrap(), Some(frame([0.0, 0.0])));
+        assert!(difference.push(&Frame::zeros(1, 2)).is_err());
+
+        let mut boxcar = FrameFilter::new(TemporalFilter::Boxcar(3)).unwrap();
+        let outputs: Vec<_> = (0..5).map(|i| boxcar.push(&frame([i as f32, 10.0])).unwrap()).collect();
+        assert_eq!(outputs, [None, None, Some(frame([1.0, 10.0])), Some(frame([2.0, 10.0])), Some(frame([3.0, 10.0]))]);
+        boxcar.reset();
+        assert_eq!(boxcar.push(&frame([0.0, 0.0])).unwrap(), None);
+
+        let mut ema = FrameFilter::new(TemporalFilter::Ema(0.5)).unwrap();
+        assert_eq!(ema.push(&frame([4.0, 0.0])).unwrap(), Some(frame([4.0, 0.0])));
+        assert_eq!(ema.push(&frame([0.0, 8.0])).unwrap(), Some(frame([2.0, 4.0])));
+        assert_eq!(ema.push(&frame([0.0, 8.0])).unwrap(), Some(frame([1.0, 6.0])));
+
+        assert!(FrameFilter::new(TemporalFilter::Boxcar(0)).is_err());
+        assert!(FrameFilter::new(TemporalFilter::Ema(0.0)).is_err());
+        assert!(FrameFilter::new(TemporalFilter::Ema(f32::NAN)).is_err());
+    }
+}
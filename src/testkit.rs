@@ -0,0 +1,296 @@
+//! Golden-file regression testing of milk command sequences.
+//!
+//! A `GoldenTest` runs a sequence of commands, waits for them to finish, and
+//! compares the files they produced against stored "golden" copies. Text
+//! outputs (e.g., from `writef2file` or ASCII image dumps) are compared token
+//! by token, with numbers allowed to differ by a tolerance; other files (e.g.,
+//! FITS) must match byte for byte.
+//!
+//! Run with the environment variable `MILKRS_UPDATE_GOLDEN=1` to (re)write
+//! the golden files from the current outputs instead of comparing.
+//!
+//! # Example
+//! ```
+//! use milkrs::Milk;
+//! use milkrs::testkit::GoldenTest;
+//! # std::fs::create_dir_all("/tmp/golden").unwrap();
+//! # std::fs::write("/tmp/golden/gain.txt", "0.5\n").unwrap();
+//! let mut milk = Milk::new().unwrap();
+//! GoldenTest::new("/tmp/golden")
+//!     .cmd("writef2file \"/tmp/gain.txt\" 0.5")
+//!     .capture("/tmp/gain.txt")
+//!     .tolerance(1e-6)
+//!     .run(&mut milk)
+//!     .unwrap();
+//! ```
+//!
+//! # Isolated sessions
+//!
+//! With the `test-support` feature, `TestSession` gives each test its own
+//! milk session which can't see or clobber the streams of any other, so
+//! that milk-touching tests can run in parallel.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Milk, Result};
+#[cfg(feature = "test-support")]
+use crate::{MilkBuilder, ScratchDir};
+#[cfg(feature = "test-support")]
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "test-support")]
+use std::sync::Once;
+#[cfg(feature = "test-support")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "test-support")]
+use std::time::Duration;
+
+/// A command sequence and the output files to check once it has run.
+#[derive(Debug, Clone)]
+pub struct GoldenTest {
+    golden_dir: PathBuf,
+    commands: Vec<String>,
+    outputs: Vec<PathBuf>,
+    tolerance: f64,
+}
+
+impl GoldenTest {
+    /// Test whose golden files live in `golden_dir`, named after the file
+    /// name of each captured output.
+    pub fn new(golden_dir: impl AsRef<Path>) -> Self {
+        Self {
+            golden_dir: golden_dir.as_ref().to_path_buf(),
+            commands: Vec::new(),
+            outputs: Vec::new(),
+            tolerance: 0.0,
+        }
+    }
+
+    /// Add a command to the sequence.
+    pub fn cmd(mut self, command: &str) -> Self {
+        self.commands.push(command.to_string());
+        self
+    }
+
+    /// Check the file at `path` (written by the commands) against its golden
+    /// copy. Any stale copy is deleted before the commands run.
+    pub fn capture(mut self, path: impl AsRef<Path>) -> Self {
+        self.outputs.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Allowed absolute or relative difference between numbers in text
+    /// outputs (whichever is more lenient). Defaults to exact.
+    pub fn tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Run the commands on `milk`, wait for them, and compare every captured
+    /// output, failing with a description of each mismatch.
+    pub fn run(&self, milk: &mut Milk) -> Result<()> {
+        for output in &self.outputs {
+            let _ = fs::remove_file(output);
+        }
+        for command in &self.commands {
+            milk.cmd(command)?;
+        }
+        milk.sync()?;
+
+        let update = env::var("MILKRS_UPDATE_GOLDEN").is_ok_and(|v| v == "1");
+        let mut failures = Vec::new();
+        for output in &self.outputs {
+            let name = output.file_name().ok_or_else(|| format!("{} isn't a file", output.display()))?;
+            let golden = self.golden_dir.join(name);
+            let actual = fs::read(output)
+                .map_err(|e| format!("couldn't read output {}: {e}", output.display()))?;
+            if update {
+                fs::create_dir_all(&self.golden_dir)?;
+                fs::write(&golden, &actual)?;
+                continue;
+            }
+            let expected = fs::read(&golden)
+                .map_err(|e| format!("couldn't read golden file {}: {e}", golden.display()))?;
+            if let Err(e) = compare(&actual, &expected, self.tolerance) {
+                failures.push(format!("{}: {e}", output.display()));
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("\n").into())
+        }
+    }
+}
+
+/// Compare file contents: token by token with numeric tolerance if both are
+/// text, byte for byte otherwise.
+pub fn compare(actual: &[u8], expected: &[u8], tolerance: f64) -> Result<()> {
+    match (std::str::from_utf8(actual), std::str::from_utf8(expected)) {
+        (Ok(actual), Ok(expected)) => compare_text(actual, expected, tolerance),
+        _ if actual == expected => Ok(()),
+        _ => Err(format!(
+            "binary contents differ ({} bytes vs {} expected)", actual.len(), expected.len()
+        ).into()),
+    }
+}
+
+/// Compare whitespace-separated tokens, allowing numeric tokens to differ by
+/// `tolerance` (absolute or relative, whichever is more lenient).
+pub fn compare_text(actual: &str, expected: &str, tolerance: f64) -> Result<()> {
+    let mut actual_tokens = actual.split_whitespace();
+    let mut expected_tokens = expected.split_whitespace();
+    let mut i = 0;
+    loop {
+        match (actual_tokens.next(), expected_tokens.next()) {
+            (None, None) => return Ok(()),
+            (Some(a), Some(e)) => {
+                let matches = match (a.parse::<f64>(), e.parse::<f64>()) {
+                    // equal infinities differ by NaN, so are checked first
+                    (Ok(a), Ok(e)) if a == e || (a.is_nan() && e.is_nan()) => true,
+                    (Ok(a), Ok(e)) => {
+                        let diff = (a - e).abs();
+                        diff <= tolerance || diff <= tolerance * e.abs()
+                    }
+                    _ => a == e,
+                };
+                if !matches {
+                    return Err(format!("token {i}: got `{a}`, expected `{e}`").into());
+                }
+            }
+            (Some(a), None) => return Err(format!("token {i}: got extra `{a}`").into()),
+            (None, Some(e)) => return Err(format!("token {i}: missing `{e}`").into()),
+        }
+        i += 1;
+    }
+}
+
+/// A milk session isolated for one test: its image and stream names get a
+/// unique prefix (`MilkBuilder::name_prefix()`), its shared memory goes in a
+/// private `MILK_SHM_DIR`, and its fifo in a private scratch directory. When
+/// it is dropped, milk is killed rather than asked to exit, and the shared
+/// memory directory is removed. The first session made by a test run also
+/// sweeps up after earlier runs which crashed (see `gc()`).
+///
+/// Derefs to the `Milk` session.
+///
+/// # Example
+/// ```
+/// use milkrs::testkit::TestSession;
+/// let mut milk = TestSession::new().unwrap();
+/// let im = milk.mk2d("im1", 64, 64)?;
+/// assert!(im.name().ends_with("_im1"));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[cfg(feature = "test-support")]
+pub struct TestSession {
+    milk: Milk,
+    shm_dir: ScratchDir,
+}
+
+#[cfg(feature = "test-support")]
+impl TestSession {
+    /// Isolated session with default settings.
+    pub fn new() -> Result<Self> {
+        Self::with_builder(Milk::builder())
+    }
+
+    /// Isolated session configured by `builder`, whose name prefix and
+    /// `MILK_SHM_DIR` are overridden.
+    pub fn with_builder(builder: MilkBuilder) -> Result<Self> {
+        static SWEPT: Once = Once::new();
+        static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+        SWEPT.call_once(|| {
+            let _ = crate::gc();
+        });
+        let n = SESSIONS.fetch_add(1, Ordering::Relaxed);
+        let id = format!("{}_{n}", std::process::id());
+        let shm_dir = ScratchDir::create(&format!("test-shm-{id}"))?;
+        let milk = builder
+            .name_prefix(&format!("t{id}_"))
+            .env("MILK_SHM_DIR", &shm_dir.path().to_string_lossy())
+            .build()?;
+        Ok(Self { milk, shm_dir })
+    }
+
+    /// The private shared memory directory milk was given.
+    pub fn shm_dir(&self) -> &Path {
+        self.shm_dir.path()
+    }
+}
+
+#[cfg(feature = "test-support")]
+impl Deref for TestSession {
+    type Target = Milk;
+
+    fn deref(&self) -> &Milk {
+        &self.milk
+    }
+}
+
+#[cfg(feature = "test-support")]
+impl DerefMut for TestSession {
+    fn deref_mut(&mut self) -> &mut Milk {
+        &mut self.milk
+    }
+}
+
+#[cfg(feature = "test-support")]
+impl Drop for TestSession {
+    fn drop(&mut self) {
+        // a test which failed part way may have left milk busy or wedged
+        let _ = self.milk.kill(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, compare_text, GoldenTest};
+    use crate::Milk;
+    use std::fs;
+
+    #[test]
+    fn text_compares_with_tolerance(){
+        assert!(compare_text("1.0 2.0\nok", "1.0000001  2.0 ok", 1e-6).is_ok());
+        assert!(compare_text("1.0 2.1", "1.0 2.0", 1e-6).is_err());
+        assert!(compare_text("1000.5", "1000.0", 1e-3).is_ok());
+        assert!(compare_text("1 2", "1 2 3", 0.0).is_err());
+        assert!(compare_text("ok", "OK", 0.0).is_err());
+        assert!(compare_text("inf -inf nan", "inf -inf NaN", 0.0).is_ok());
+        assert!(compare_text("inf", "-inf", 0.0).is_err());
+        assert!(compare(&[0xff, 1], &[0xff, 1], 0.0).is_ok());
+        assert!(compare(&[0xff, 1], &[0xff, 2], 0.0).is_err());
+    }
+
+    #[test]
+    fn golden_outputs_are_checked(){
+        let golden_dir = "/tmp/milkrs_golden_test";
+        fs::create_dir_all(golden_dir).unwrap();
+        fs::write(format!("{golden_dir}/golden_out.txt"), "0.25\n").unwrap();
+        let mut milk = Milk::new().expect("Failed to start milk");
+        let test = GoldenTest::new(golden_dir)
+            .capture("/tmp/golden_out.txt")
+            .tolerance(1e-3);
+        test.clone()
+            .cmd("writef2file \"/tmp/golden_out.txt\" 0.2501")
+            .run(&mut milk)
+            .expect("golden test failed");
+        assert!(test.cmd("writef2file \"/tmp/golden_out.txt\" 0.3").run(&mut milk).is_err());
+    }
+
+    #[cfg(feature = "test-support")]
+    #[test]
+    fn test_sessions_are_isolated(){
+        use super::TestSession;
+        let mut a = TestSession::new().expect("couldn't start session");
+        let b = TestSession::new().expect("couldn't start session");
+        assert_ne!(a.prefixed("im"), b.prefixed("im"));
+        assert_ne!(a.shm_dir(), b.shm_dir());
+        a.sync().expect("couldn't sync");
+        let shm_dir = a.shm_dir().to_path_buf();
+        let scratch = a.scratch_dir().expect("no scratch dir").path().to_path_buf();
+        assert!(shm_dir.exists());
+        drop(a);
+        assert!(!shm_dir.exists() && !scratch.exists());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{Milk, Result};
+
+/// A group of commands which either all stick, or are undone by running the
+/// compensating ("undo") commands registered with them, most recent first.
+/// Obtain one with `Milk::transaction()`.
+///
+/// Unless `commit()` is called, the undo commands run when the transaction is
+/// dropped - so returning early with `?` after a failed command cleans up
+/// whatever the group had created so far.
+///
+/// # Example
+/// ```
+/// use milkrs::Milk;
+/// fn make_psf(milk: &mut Milk) -> Result<(), Box<dyn std::error::Error>> {
+///     let mut tx = milk.transaction();
+///     tx.cmd_with_undo("mk2Dim tmp1 256 256", "rmim tmp1")?;
+///     tx.cmd_with_undo("mk2Dim tmp2 256 256", "rmim tmp2")?;
+///     tx.cmd("imcp2shm tmp1 psf")?;  // if this fails, tmp2 then tmp1 are removed
+///     tx.commit();
+///     Ok(())
+/// }
+/// let mut milk = Milk::new().unwrap();
+/// make_psf(&mut milk).unwrap();
+/// ```
+pub struct Transaction<'a> {
+    milk: &'a mut Milk,
+    undo: Vec<String>,
+    done: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(milk: &'a mut Milk) -> Self {
+        Self {
+            milk,
+            undo: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Send a command which needs no undoing.
+    pub fn cmd(&mut self, command: &str) -> Result<()> {
+        self.milk.cmd(command)
+    }
+
+    /// Send a command, and if it is sent successfully, register `undo` to be
+    /// run if the transaction is aborted.
+    pub fn cmd_with_undo(&mut self, command: &str, undo: &str) -> Result<()> {
+        self.milk.cmd(command)?;
+        self.undo.push(undo.to_string());
+        Ok(())
+    }
+
+    /// The session the transaction is running in, e.g., to `sync()` midway.
+    pub fn milk(&mut self) -> &mut Milk {
+        self.milk
+    }
+
+    /// Keep everything the transaction did, forgetting the undo commands.
+    pub fn commit(mut self) {
+        self.done = true;
+    }
+
+    /// Undo everything the transaction did, by sending the undo commands in
+    /// reverse order as one batch.
+    pub fn abort(mut self) -> Result<()> {
+        self.run_undo()
+    }
+
+    fn run_undo(&mut self) -> Result<()> {
+        self.done = true;
+        if self.undo.is_empty() {
+            return Ok(());
+        }
+        let undo = std::mem::take(&mut self.undo);
+        self.milk.cmds(undo.iter().rev().map(String::as_str).collect())
+    }
+}
+
+impl Drop for Transaction<'_> {
+    /// Abort the transaction if it was neither committed nor aborted. Errors
+    /// sending the undo commands are ignored, as the session is most likely
+    /// gone if they happen.
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.run_undo();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Milk;
+
+    #[test]
+    fn undoes_uncommitted_transactions(){
+        let mut milk = Milk::builder().dry_run(true).build().expect("dry run failed");
+        let mut tx = milk.transaction();
+        tx.cmd_with_undo("mk2Dim tmp1 64 64", "rmim tmp1").expect("couldn't record");
+        tx.cmd_with_undo("mk2Dim tmp2 64 64", "rmim tmp2").expect("couldn't record");
+        assert!(tx.cmd_with_undo("bad\ncommand", "never run").is_err());
+        drop(tx);
+        let mut tx = milk.transaction();
+        tx.cmd_with_undo("mk2Dim im3 64 64", "rmim im3").expect("couldn't record");
+        tx.commit();
+        assert_eq!(
+            milk.recorded_commands().expect("not a dry run"),
+            ["mk2Dim tmp1 64 64", "mk2Dim tmp2 64 64", "rmim tmp2", "rmim tmp1", "mk2Dim im3 64 64"],
+        );
+    }
+}